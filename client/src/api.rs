@@ -1,16 +1,76 @@
 use std::collections::HashMap;
 
-use anyhow::Result;
-use reqwest::Client;
+use anyhow::{anyhow, Result};
+use reqwest::{Client, Method, RequestBuilder, StatusCode, Url};
 use serde::Deserialize;
 
-use crate::config::{Configuration, Tunnel};
+use crate::{
+    config::{Configuration, Mode, Protocol, Tunnel},
+    tls::http_client,
+};
+
+const SESSION_HEADER: &str = "X-Session-Id";
+
+/// A client session registered with the edge, kept alive by renewing its lease.
+#[derive(Clone)]
+pub struct Session {
+    pub id: String,
+    /// Seconds the session stays alive without being renewed.
+    pub lease: u64,
+}
+
+/// Outcome of a session lease renewal.
+pub enum Renewal {
+    Renewed,
+    /// The edge does not know the session, e.g. because it restarted.
+    Unknown,
+    /// The session was closed through `goodbye`.
+    Closed,
+    /// The edge is shutting down and will not take new connections.
+    ShuttingDown,
+}
+
+/// A tunnel as the edge sees it.
+#[derive(Deserialize)]
+pub struct TunnelStatus {
+    pub name: String,
+    pub target: String,
+    pub protocol: Protocol,
+    pub mode: Mode,
+    pub port: u16,
+    pub created_at: u64,
+    pub connections: ConnectionCounts,
+    pub idle_workers: usize,
+}
+
+#[derive(Deserialize)]
+pub struct ConnectionCounts {
+    pub active: usize,
+    pub total: u64,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct ListResponse {
+    status: String,
+    tunnels: Vec<TunnelStatus>,
+}
 
 #[allow(dead_code)]
 #[derive(Deserialize)]
 struct ConnectResponse {
     status: String,
     worker: u16,
+    #[serde(default)]
+    tls: bool,
+}
+
+/// Where HolePunch workers connect to.
+#[derive(Clone, Copy)]
+pub struct WorkerServer {
+    pub port: u16,
+    /// Whether workers have to connect over TLS.
+    pub tls: bool,
 }
 
 #[allow(dead_code)]
@@ -19,104 +79,221 @@ struct StatusResponse {
     status: String,
 }
 
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct SessionResponse {
+    status: String,
+    session: String,
+    lease: u64,
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize)]
 struct EdgeResponse {
     status: String,
+    #[serde(default)]
     port: u16,
 }
 
-pub async fn check_authorization(cfg: &Configuration) -> Result<bool> {
-    let url = format!("{}/api/v1/check_authorization", cfg.edge);
+/// The edge's API. The HTTP client is set up once, with the client
+/// certificate if there is one, and shared by every request.
+#[derive(Clone)]
+pub struct EdgeApi {
+    http: Client,
+    edge: String,
+    secret_key: String,
+}
 
-    let response = Client::new()
-        .get(&url)
-        .bearer_auth(&cfg.secret_key)
-        .send()
-        .await?;
+impl EdgeApi {
+    pub fn new(cfg: &Configuration) -> Result<Self> {
+        Ok(Self {
+            http: http_client(cfg)?,
+            edge: cfg.edge.clone(),
+            secret_key: cfg.secret_key.clone(),
+        })
+    }
 
-    Ok(response.status().is_success())
-}
+    /// Starts a request to the edge, authenticated with the secret key, or
+    /// with the client certificate when there is no key.
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let request = self.http.request(method, url);
 
-pub async fn connect(cfg: &Configuration) -> Result<u16> {
-    let url = format!("{}/api/v1/connect", cfg.edge);
+        if self.secret_key.is_empty() {
+            request
+        } else {
+            request.bearer_auth(&self.secret_key)
+        }
+    }
 
-    let response = Client::new()
-        .get(&url)
-        .bearer_auth(&cfg.secret_key)
-        .send()
-        .await?;
+    pub async fn check_authorization(&self) -> Result<bool> {
+        let url = format!("{}/api/v1/check_authorization", self.edge);
 
-    let response: ConnectResponse = response.json().await?;
-    Ok(response.worker)
-}
+        let response = self.request(Method::GET, &url).send().await?;
 
-pub async fn goodbye(cfg: &Configuration) -> Result<String> {
-    let url = format!("{}/api/v1/goodbye", cfg.edge);
+        Ok(response.status().is_success())
+    }
 
-    let response = Client::new()
-        .get(&url)
-        .bearer_auth(&cfg.secret_key)
-        .send()
-        .await?;
+    pub async fn connect(&self) -> Result<WorkerServer> {
+        let url = format!("{}/api/v1/connect", self.edge);
 
-    let response: StatusResponse = response.json().await?;
-    Ok(response.status)
-}
+        let response = self.request(Method::GET, &url).send().await?;
 
-pub async fn create_edge(
-    cfg: &Configuration,
-    name: String,
-    tunnel: &Tunnel,
-) -> Result<(String, String)> {
-    let url = format!("{}/api/v1/edge", cfg.edge);
-
-    let mut params = HashMap::new();
-    params.insert("name", name);
-    params.insert("target", tunnel.target.clone());
-    params.insert("protocol", format!("{:?}", tunnel.protocol));
-    params.insert("mode", format!("{:?}", tunnel.mode));
-
-    let response = Client::new()
-        .post(&url)
-        .bearer_auth(&cfg.secret_key)
-        .form(&params)
-        .send()
-        .await?;
-
-    let response: EdgeResponse = response.json().await?;
-    Ok((
-        response.status,
-        format!("{}:{}", cfg.edge_ip, response.port),
-    ))
-}
+        let response: ConnectResponse = response.json().await?;
+        Ok(WorkerServer {
+            port: response.worker,
+            tls: response.tls,
+        })
+    }
 
-pub async fn delete_edge(cfg: &Configuration, tunnel: &Tunnel) -> Result<String> {
-    let url = format!("{}/api/v1/edge", cfg.edge);
+    pub async fn open_session(&self) -> Result<Session> {
+        let url = format!("{}/api/v1/session", self.edge);
 
-    let mut params = HashMap::new();
-    params.insert("target", tunnel.target.clone());
+        let response = self.request(Method::POST, &url).send().await?;
 
-    let response = Client::new()
-        .delete(&url)
-        .bearer_auth(&cfg.secret_key)
-        .form(&params)
-        .send()
-        .await?;
+        let response: SessionResponse = response.json().await?;
+        Ok(Session {
+            id: response.session,
+            lease: response.lease,
+        })
+    }
 
-    let response: StatusResponse = response.json().await?;
-    Ok(response.status)
-}
+    pub async fn renew_session(&self, session: &Session) -> Result<Renewal> {
+        let url = format!("{}/api/v1/session/renew", self.edge);
+
+        let response = self
+            .request(Method::POST, &url)
+            .header(SESSION_HEADER, &session.id)
+            .send()
+            .await?;
+
+        Ok(match response.status() {
+            status if status.is_success() => Renewal::Renewed,
+            StatusCode::GONE => Renewal::Closed,
+            StatusCode::SERVICE_UNAVAILABLE => Renewal::ShuttingDown,
+            _ => Renewal::Unknown,
+        })
+    }
+
+    pub async fn goodbye(&self, session: &Session) -> Result<String> {
+        let url = format!("{}/api/v1/goodbye", self.edge);
+
+        let response = self
+            .request(Method::GET, &url)
+            .header(SESSION_HEADER, &session.id)
+            .send()
+            .await?;
+
+        let response: StatusResponse = response.json().await?;
+        Ok(response.status)
+    }
+
+    pub async fn list_edges(&self, session: &Session) -> Result<Vec<TunnelStatus>> {
+        let url = format!("{}/api/v1/edge", self.edge);
+
+        let response = self
+            .request(Method::GET, &url)
+            .header(SESSION_HEADER, &session.id)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response: ListResponse = response.json().await?;
+        Ok(response.tunnels)
+    }
+
+    pub async fn create_edge(
+        &self,
+        session: &Session,
+        name: String,
+        tunnel: &Tunnel,
+        port: Option<u16>,
+    ) -> Result<(String, u16)> {
+        let url = format!("{}/api/v1/edge", self.edge);
+
+        let mut params = HashMap::new();
+        params.insert("name", name);
+        params.insert("target", tunnel.target.clone());
+        params.insert("protocol", format!("{:?}", tunnel.protocol));
+        params.insert("mode", format!("{:?}", tunnel.mode));
+
+        if let Some(port) = port {
+            params.insert("port", port.to_string());
+        }
+
+        if let Some(max_connections) = tunnel.max_connections {
+            params.insert("max_connections", max_connections.to_string());
+        }
+
+        let response = self
+            .request(Method::POST, &url)
+            .header(SESSION_HEADER, &session.id)
+            .form(&params)
+            .send()
+            .await?;
+
+        let response: EdgeResponse = response.json().await?;
+        Ok((response.status, response.port))
+    }
+
+    pub async fn update_edge(
+        &self,
+        session: &Session,
+        name: &str,
+        tunnel: &Tunnel,
+    ) -> Result<(String, u16)> {
+        // The name is a path segment here, so it is percent-encoded.
+        let mut url = Url::parse(&format!("{}/api/v1/edge", self.edge))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("edge url {} can't have a path", self.edge))?
+            .push(name);
+
+        let mut params = HashMap::new();
+        params.insert("target", tunnel.target.clone());
+        params.insert("protocol", format!("{:?}", tunnel.protocol));
+        params.insert("mode", format!("{:?}", tunnel.mode));
+        params.insert(
+            "max_connections",
+            tunnel.max_connections.unwrap_or_default().to_string(),
+        );
+
+        let response = self
+            .request(Method::PATCH, url.as_str())
+            .header(SESSION_HEADER, &session.id)
+            .form(&params)
+            .send()
+            .await?;
+
+        let response: EdgeResponse = response.json().await?;
+        Ok((response.status, response.port))
+    }
+
+    pub async fn delete_edge(&self, session: &Session, name: &str) -> Result<String> {
+        let url = format!("{}/api/v1/edge", self.edge);
+
+        let mut params = HashMap::new();
+        params.insert("name", name.to_string());
+
+        let response = self
+            .request(Method::DELETE, &url)
+            .header(SESSION_HEADER, &session.id)
+            .form(&params)
+            .send()
+            .await?;
+
+        let response: StatusResponse = response.json().await?;
+        Ok(response.status)
+    }
 
-pub async fn delete_edges(cfg: &Configuration) -> Result<String> {
-    let url = format!("{}/api/v1/edge/all", cfg.edge);
+    pub async fn delete_edges(&self, session: &Session) -> Result<String> {
+        let url = format!("{}/api/v1/edge/all", self.edge);
 
-    let response = Client::new()
-        .delete(&url)
-        .bearer_auth(&cfg.secret_key)
-        .send()
-        .await?;
+        let response = self
+            .request(Method::DELETE, &url)
+            .header(SESSION_HEADER, &session.id)
+            .send()
+            .await?;
 
-    let response: StatusResponse = response.json().await?;
-    Ok(response.status)
+        let response: StatusResponse = response.json().await?;
+        Ok(response.status)
+    }
 }
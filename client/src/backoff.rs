@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use rand::random;
+
+/// Exponential backoff with jitter, used whenever the edge is unreachable.
+pub struct Backoff {
+    attempt: u32,
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            attempt: 0,
+            base,
+            max,
+        }
+    }
+
+    /// Returns the delay before the next attempt, somewhere between half
+    /// and all of the current exponential step.
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+
+        self.attempt = self.attempt.saturating_add(1);
+
+        step / 2 + step.mul_f64(random::<f64>() / 2.0)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
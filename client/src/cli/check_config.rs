@@ -0,0 +1,204 @@
+use std::{
+    fs::{metadata, read_to_string},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::Url;
+use toml::{from_str, Table, Value};
+
+use crate::{
+    cli::ConnectionArgs,
+    config::{resolve_target, Mode, TlsConfig, Tunnel},
+};
+
+/// Validates the configuration file and reports every problem found, with
+/// the key it was found at.
+pub fn check_config(path: &Path, connection: &ConnectionArgs) -> Result<()> {
+    let file = read_to_string(path)
+        .map_err(|e| anyhow!("unable to read config {}: {e}", path.display()))?;
+    let mut table: Table = from_str(&file).map_err(|e| anyhow!("{}: {e}", path.display()))?;
+
+    // Overrides take part in the check, as the client would use them too.
+    for (key, value) in [
+        ("secret_key", &connection.secret_key),
+        ("edge", &connection.edge),
+        ("edge_ip", &connection.edge_ip),
+    ] {
+        if let Some(value) = value {
+            table.insert(key.to_string(), Value::String(value.clone()));
+        }
+    }
+    if let Some(inspector) = connection.inspector {
+        table.insert("inspector".to_string(), Value::Integer(inspector as i64));
+    }
+    if let Some(idle_workers) = connection.idle_workers {
+        table.insert(
+            "idle_workers".to_string(),
+            Value::Integer(idle_workers as i64),
+        );
+    }
+    for (key, value) in [
+        ("cert", &connection.tls_cert),
+        ("key", &connection.tls_key),
+        ("ca", &connection.tls_ca),
+    ] {
+        if let Some(value) = value {
+            if let Value::Table(tls) = table
+                .entry("tls")
+                .or_insert_with(|| Value::Table(Table::new()))
+            {
+                tls.insert(key.to_string(), Value::String(value.display().to_string()));
+            }
+        }
+    }
+
+    let mut problems = vec![];
+
+    let tls = match table.get("tls") {
+        None => TlsConfig::default(),
+        Some(value) => value
+            .clone()
+            .try_into()
+            .unwrap_or_else(|e: toml::de::Error| {
+                problems.push(("tls".to_string(), e.to_string().trim().replace('\n', " ")));
+                TlsConfig::default()
+            }),
+    };
+
+    for (key, path) in [("cert", &tls.cert), ("key", &tls.key), ("ca", &tls.ca)] {
+        let Some(path) = path else {
+            continue;
+        };
+
+        if let Err(e) = metadata(path) {
+            problems.push((
+                format!("tls.{key}"),
+                format!("unable to read {}: {e}", path.display()),
+            ));
+        }
+    }
+
+    if tls.cert.is_some() != tls.key.is_some() {
+        problems.push((
+            "tls".to_string(),
+            "a client certificate needs both cert and key".to_string(),
+        ));
+    }
+
+    // A client certificate replaces the secret key.
+    let required = match tls.cert {
+        Some(_) => vec!["edge"],
+        None => vec!["secret_key", "edge"],
+    };
+
+    for key in required {
+        match table.get(key) {
+            None => problems.push((key.to_string(), "missing".to_string())),
+            Some(Value::String(value)) if value.is_empty() => {
+                problems.push((key.to_string(), "must not be empty".to_string()))
+            }
+            Some(Value::String(_)) => {}
+            Some(other) => problems.push((
+                key.to_string(),
+                format!("expected a string, found {}", other.type_str()),
+            )),
+        }
+    }
+
+    if let Some(Value::String(edge)) = table.get("edge") {
+        match Url::parse(edge) {
+            Ok(url) if !["http", "https"].contains(&url.scheme()) => problems.push((
+                "edge".to_string(),
+                format!("unsupported scheme {}", url.scheme()),
+            )),
+            Ok(url) if url.host_str().is_none() && !table.contains_key("edge_ip") => {
+                problems.push(("edge".to_string(), "url has no host".to_string()))
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(("edge".to_string(), format!("invalid url: {e}"))),
+        }
+    }
+
+    match table.get("edge_ip") {
+        None | Some(Value::String(_)) => {}
+        Some(other) => problems.push((
+            "edge_ip".to_string(),
+            format!("expected a string, found {}", other.type_str()),
+        )),
+    }
+
+    let idle_workers = match table.get("idle_workers") {
+        None => 0,
+        Some(Value::Integer(value)) if *value >= 0 => *value,
+        Some(other) => {
+            problems.push((
+                "idle_workers".to_string(),
+                format!("expected a positive integer, found {other}"),
+            ));
+            0
+        }
+    };
+
+    match table.get("inspector") {
+        None => {}
+        Some(Value::Integer(port)) if (1..=u16::MAX as i64).contains(port) => {}
+        Some(other) => problems.push(("inspector".to_string(), format!("invalid port {other}"))),
+    }
+
+    match table.get("tunnels") {
+        None => problems.push(("tunnels".to_string(), "no tunnels defined".to_string())),
+        Some(Value::Table(tunnels)) => {
+            if tunnels.is_empty() {
+                problems.push(("tunnels".to_string(), "no tunnels defined".to_string()));
+            }
+
+            let mut hole_punch = vec![];
+
+            for (name, value) in tunnels {
+                let location = format!("tunnels.{name}");
+
+                let tunnel: Tunnel = match value.clone().try_into() {
+                    Ok(tunnel) => tunnel,
+                    Err(e) => {
+                        problems.push((location, e.to_string().trim().replace('\n', " ")));
+                        continue;
+                    }
+                };
+
+                if let Err(e) = resolve_target(&tunnel.target) {
+                    problems.push((format!("{location}.target"), e.to_string()));
+                }
+
+                if let Mode::HolePunch = tunnel.mode {
+                    hole_punch.push(name.clone());
+                }
+            }
+
+            if idle_workers == 0 && !hole_punch.is_empty() {
+                problems.push((
+                    "idle_workers".to_string(),
+                    format!(
+                        "must be greater than 0, HolePunch tunnels need workers ({})",
+                        hole_punch.join(", ")
+                    ),
+                ));
+            }
+        }
+        Some(other) => problems.push((
+            "tunnels".to_string(),
+            format!("expected a table, found {}", other.type_str()),
+        )),
+    }
+
+    if problems.is_empty() {
+        println!("{}: ok", path.display());
+        return Ok(());
+    }
+
+    for (location, message) in &problems {
+        println!("{}: {location}: {message}", path.display());
+    }
+
+    bail!("{} problem(s) found in {}", problems.len(), path.display())
+}
@@ -0,0 +1,20 @@
+use std::path::Path;
+
+use anyhow::Result;
+use log::info;
+
+use crate::{
+    api::EdgeApi,
+    config::Configuration,
+    session::{load_session, remove_session},
+};
+
+pub async fn down(cfg: &Configuration, session_path: &Path) -> Result<()> {
+    let session = load_session(session_path)?;
+
+    let response = EdgeApi::new(cfg)?.goodbye(&session).await?;
+    remove_session(session_path);
+
+    info!("edge server said: {response}");
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Parser, Subcommand};
+use logging::LogFormat;
+use reqwest::Url;
+
+use crate::config::{Configuration, Mode, Protocol, Tunnel};
+
+pub mod check_config;
+pub mod down;
+pub mod run;
+pub mod status;
+
+#[derive(Parser, Debug)]
+#[command(name = "fast-reverse-proxy-client")]
+#[command(version = env!("CARGO_PKG_VERSION"))]
+#[command(about = "Expose local services through a fast-reverse-proxy edge")]
+#[command(propagate_version = true)]
+pub struct Cli {
+    /// Path to the configuration file
+    #[arg(long, global = true, env = "CLIENT_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Format of the log output
+    #[arg(
+        long,
+        global = true,
+        env = "CLIENT_LOG_FORMAT",
+        value_enum,
+        default_value_t
+    )]
+    pub log_format: LogFormat,
+
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+/// Settings that override the configuration file, so the client can run without one.
+#[derive(Args, Debug)]
+pub struct ConnectionArgs {
+    /// Secret key generated by the edge
+    #[arg(long, global = true, env = "CLIENT_SECRET_KEY", hide_env_values = true)]
+    pub secret_key: Option<String>,
+    /// Edge API url
+    #[arg(long, global = true, env = "CLIENT_EDGE")]
+    pub edge: Option<String>,
+    /// Edge IP used for worker connections, defaults to the host of the edge url
+    #[arg(long, global = true, env = "CLIENT_EDGE_IP")]
+    pub edge_ip: Option<String>,
+    /// Number of idle workers, only used for HolePunch mode
+    #[arg(long, global = true, env = "CLIENT_IDLE_WORKERS")]
+    pub idle_workers: Option<usize>,
+    /// Serve the local inspector on this port, e.g. 4040
+    #[arg(long, global = true, env = "CLIENT_INSPECTOR")]
+    pub inspector: Option<u16>,
+    /// Client certificate to authenticate with instead of a secret key
+    #[arg(long, global = true, env = "CLIENT_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// Private key of the client certificate
+    #[arg(long, global = true, env = "CLIENT_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    /// CA certificate the edge's certificate is checked against
+    #[arg(long, global = true, env = "CLIENT_TLS_CA")]
+    pub tls_ca: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Connect to the edge and expose the configured tunnels (default)
+    Run {},
+    /// Expose a single local TCP port without a config file
+    Tcp {
+        port: u16,
+        /// Name of the tunnel, defaults to tcp-<port>
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long, default_value = "Tcp")]
+        protocol: Protocol,
+        #[arg(long, default_value = "Reverse")]
+        mode: Mode,
+    },
+    /// Show this client's tunnels as the edge sees them
+    Status {},
+    /// Close this client's tunnels and stop the running client
+    Down {},
+    /// Validate the configuration file and report every problem
+    CheckConfig {},
+}
+
+impl ConnectionArgs {
+    pub fn apply(&self, cfg: &mut Configuration) -> Result<()> {
+        if let Some(secret_key) = &self.secret_key {
+            cfg.secret_key = secret_key.clone();
+        }
+        if let Some(edge) = &self.edge {
+            cfg.edge = edge.clone();
+        }
+        if let Some(edge_ip) = &self.edge_ip {
+            cfg.edge_ip = edge_ip.clone();
+        }
+        if let Some(idle_workers) = self.idle_workers {
+            cfg.idle_workers = idle_workers;
+        }
+        if let Some(inspector) = self.inspector {
+            cfg.inspector = Some(inspector);
+        }
+        if let Some(tls_cert) = &self.tls_cert {
+            cfg.tls.cert = Some(tls_cert.clone());
+        }
+        if let Some(tls_key) = &self.tls_key {
+            cfg.tls.key = Some(tls_key.clone());
+        }
+        if let Some(tls_ca) = &self.tls_ca {
+            cfg.tls.ca = Some(tls_ca.clone());
+        }
+
+        if cfg.secret_key.is_empty() && cfg.tls.cert.is_none() {
+            bail!("no secret key configured, set secret_key or tls.cert, or pass --secret-key or --tls-cert");
+        }
+        if cfg.tls.cert.is_some() != cfg.tls.key.is_some() {
+            bail!("a client certificate needs both tls.cert and tls.key");
+        }
+        if cfg.edge.is_empty() {
+            bail!("no edge configured, set edge or pass --edge");
+        }
+        if cfg.edge_ip.is_empty() {
+            cfg.edge_ip = Url::parse(&cfg.edge)?
+                .host_str()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("edge url {} has no host", cfg.edge))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces the configured tunnels with a single one pointing at a local port.
+pub fn ad_hoc_tunnel(
+    cfg: &mut Configuration,
+    port: u16,
+    name: Option<String>,
+    protocol: Protocol,
+    mode: Mode,
+) {
+    let name = name.unwrap_or_else(|| format!("tcp-{port}"));
+
+    // Reverse tunnels have no use for workers, HolePunch ones need a few.
+    cfg.idle_workers = match mode {
+        Mode::HolePunch if cfg.idle_workers == 0 => 5,
+        Mode::HolePunch => cfg.idle_workers,
+        Mode::Reverse => 0,
+    };
+
+    cfg.tunnels.clear();
+    cfg.tunnels.insert(
+        name,
+        Tunnel {
+            target: format!("127.0.0.1:{port}"),
+            protocol,
+            mode,
+            max_connections: None,
+        },
+    );
+}
@@ -0,0 +1,315 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use anyhow::{bail, Result};
+use log::{error, info, warn};
+use tokio::{
+    select,
+    signal::ctrl_c,
+    sync::oneshot::{channel, Sender},
+    time::{interval, sleep},
+};
+
+use crate::{
+    api::{EdgeApi, Renewal, Session},
+    backoff::Backoff,
+    config::{Configuration, Tunnel},
+    inspector::{serve_inspector, Inspector},
+    session::{remove_session, save_session},
+    watcher::ConfigWatcher,
+    worker,
+};
+
+/// A live connection to the edge: the session plus the handle closing its workers.
+struct Connection {
+    session: Session,
+    close_workers: Sender<()>,
+}
+
+/// Why a session stopped being usable.
+enum SessionEnd {
+    /// The edge restarted or became unreachable, so we should reconnect.
+    Lost,
+    /// Someone called goodbye on our session, so we should stop.
+    Closed,
+}
+
+/// Runs the client until it is stopped. When `config_path` is set, changes
+/// to the file's tunnels are applied without reconnecting.
+pub async fn run(
+    cfg: &'static Configuration,
+    session_path: &Path,
+    config_path: Option<&Path>,
+) -> Result<()> {
+    if cfg.tunnels.is_empty() {
+        bail!("no tunnels defined in config.toml");
+    } else {
+        info!("booting with {} tunnels...", cfg.tunnels.len());
+    }
+
+    info!("contacting edge server at {}...", cfg.edge);
+
+    let api = EdgeApi::new(cfg)?;
+
+    if !api.check_authorization().await? {
+        bail!("failed to authorize with edge server");
+    }
+
+    let inspector = Inspector::new(cfg);
+
+    if let Some(port) = cfg.inspector {
+        let inspector = inspector.clone();
+        let api = api.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_inspector(api, inspector, port).await {
+                error!("failed to serve inspector on port {port}: {e}");
+            }
+        });
+    }
+
+    // Public ports handed out by the edge, reused when reconnecting.
+    let mut ports = HashMap::new();
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+    // Tunnels we want to expose, updated whenever the file changes.
+    let mut tunnels = cfg.tunnels.clone();
+    let mut watcher = ConfigWatcher::new(config_path);
+
+    loop {
+        // Changes made while disconnected are picked up by the next connection.
+        if let Some(new) = watcher.check() {
+            tunnels = new.tunnels;
+            inspector.set_tunnels(&tunnels);
+        }
+
+        match connect(cfg, &api, &tunnels, &mut ports, &inspector).await {
+            Ok(connection) => {
+                backoff.reset();
+
+                if let Err(e) = save_session(session_path, &connection.session) {
+                    warn!("failed to save session to {}: {e}", session_path.display());
+                }
+
+                let watch = watch_session(&api, &connection.session);
+                tokio::pin!(watch);
+
+                let end = loop {
+                    select! {
+                        _ = ctrl_c() => {
+                            info!("shutting down...");
+
+                            let _ = connection.close_workers.send(());
+                            remove_session(session_path);
+
+                            let response = api.goodbye(&connection.session).await?;
+                            info!("edge server said: {response}");
+
+                            return Ok(());
+                        }
+
+                        end = &mut watch => break end,
+
+                        new = watcher.changed() => {
+                            let session = &connection.session;
+                            reload_tunnels(cfg, &api, session, &mut tunnels, new.tunnels, &mut ports, &inspector).await;
+                        }
+                    }
+                };
+
+                let _ = connection.close_workers.send(());
+                inspector.disconnected();
+
+                match end {
+                    SessionEnd::Lost => warn!("lost connection to edge server"),
+                    SessionEnd::Closed => {
+                        info!("edge server closed our session, shutting down...");
+                        remove_session(session_path);
+                        return Ok(());
+                    }
+                }
+            }
+
+            Err(e) => error!("failed to connect to edge server: {e}"),
+        }
+
+        let delay = backoff.next_delay();
+        info!("reconnecting in {:.1}s...", delay.as_secs_f64());
+
+        select! {
+            _ = ctrl_c() => {
+                remove_session(session_path);
+                return Ok(());
+            }
+            _ = sleep(delay) => {}
+        }
+    }
+}
+
+/// Opens a session, starts the workers and (re-)creates every tunnel.
+async fn connect(
+    cfg: &Configuration,
+    api: &EdgeApi,
+    tunnels: &HashMap<String, Tunnel>,
+    ports: &mut HashMap<String, u16>,
+    inspector: &Inspector,
+) -> Result<Connection> {
+    let session = api.open_session().await?;
+
+    info!("opened session with a lease of {}s", session.lease);
+
+    let worker_server = api.connect().await?;
+
+    info!(
+        "connecting to edge worker server at port {}...",
+        worker_server.port
+    );
+
+    let (tx, rx) = channel();
+    worker::start_workers(cfg, &session, worker_server, rx, inspector.clone()).await?;
+    inspector.connected(&session);
+
+    for (id, tunnel) in tunnels {
+        create_tunnel(cfg, api, &session, id, tunnel, ports, inspector).await?;
+    }
+
+    Ok(Connection {
+        session,
+        close_workers: tx,
+    })
+}
+
+/// Creates a tunnel, asking for the port it had before if there was one.
+async fn create_tunnel(
+    cfg: &Configuration,
+    api: &EdgeApi,
+    session: &Session,
+    id: &str,
+    tunnel: &Tunnel,
+    ports: &mut HashMap<String, u16>,
+    inspector: &Inspector,
+) -> Result<()> {
+    let previous = ports.get(id).copied();
+    let (status, port) = api
+        .create_edge(session, id.to_string(), tunnel, previous)
+        .await?;
+
+    if status != "ok" {
+        bail!(
+            "failed to create tunnel {id} (proto={:?}, mode={:?}), status: {status}",
+            tunnel.protocol,
+            tunnel.mode
+        );
+    }
+
+    info!(
+        "tunnel {id} (proto={:?}, mode={:?}) created successfully -> {}:{port}",
+        tunnel.protocol, tunnel.mode, cfg.edge_ip
+    );
+
+    if previous.is_some_and(|previous| previous != port) {
+        warn!(
+            "tunnel {id} moved to a new port, it is now reachable at {}:{port}",
+            cfg.edge_ip
+        );
+    }
+
+    ports.insert(id.to_string(), port);
+    inspector.tunnel_created(id, format!("{}:{port}", cfg.edge_ip));
+
+    Ok(())
+}
+
+/// Brings the edge in line with a reloaded configuration. Only tunnels that
+/// were added, removed or changed are touched, the others keep running along
+/// with their connections and workers. Only the changes the edge accepted
+/// are kept in `current`, so failed ones are tried again on the next reload.
+async fn reload_tunnels(
+    cfg: &Configuration,
+    api: &EdgeApi,
+    session: &Session,
+    current: &mut HashMap<String, Tunnel>,
+    new: HashMap<String, Tunnel>,
+    ports: &mut HashMap<String, u16>,
+    inspector: &Inspector,
+) {
+    inspector.set_tunnels(&new);
+
+    let removed = current
+        .keys()
+        .filter(|id| !new.contains_key(*id))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for (id, tunnel) in new {
+        match current.get(&id) {
+            None => match create_tunnel(cfg, api, session, &id, &tunnel, ports, inspector).await {
+                Ok(()) => {
+                    current.insert(id, tunnel);
+                }
+                Err(e) => error!("failed to add tunnel {id}: {e}"),
+            },
+
+            Some(old) if *old != tunnel => match api.update_edge(session, &id, &tunnel).await {
+                Ok((status, _)) if status == "ok" => {
+                    info!(
+                        "tunnel {id} updated (to={}, proto={:?}, mode={:?})",
+                        tunnel.target, tunnel.protocol, tunnel.mode
+                    );
+                    current.insert(id, tunnel);
+                }
+                Ok((status, _)) => error!("failed to update tunnel {id}, status: {status}"),
+                Err(e) => error!("failed to update tunnel {id}: {e}"),
+            },
+
+            Some(_) => {}
+        }
+    }
+
+    for id in removed {
+        match api.delete_edge(session, &id).await {
+            Ok(status) if status == "ok" => {
+                info!("tunnel {id} removed");
+                current.remove(&id);
+                ports.remove(&id);
+            }
+            Ok(status) => error!("failed to remove tunnel {id}, status: {status}"),
+            Err(e) => error!("failed to remove tunnel {id}: {e}"),
+        }
+    }
+
+    // So the inspector shows what the edge serves, not what failed.
+    inspector.set_tunnels(current);
+}
+
+/// Renews the session lease until the edge stops accepting it, which
+/// happens when the edge restarts, becomes unreachable or the session
+/// is closed from elsewhere.
+async fn watch_session(api: &EdgeApi, session: &Session) -> SessionEnd {
+    let mut interval = interval(Duration::from_secs((session.lease / 3).max(1)));
+    let mut failures = 0;
+
+    loop {
+        interval.tick().await;
+
+        match api.renew_session(session).await {
+            Ok(Renewal::Renewed) => failures = 0,
+            Ok(Renewal::Closed) => return SessionEnd::Closed,
+            Ok(Renewal::ShuttingDown) => {
+                warn!("edge server is shutting down");
+                return SessionEnd::Lost;
+            }
+            Ok(Renewal::Unknown) => {
+                warn!("edge server no longer knows our session");
+                return SessionEnd::Lost;
+            }
+            Err(e) => {
+                error!("failed to renew session: {e}");
+
+                // The lease covers three renewal intervals, so one miss is survivable.
+                failures += 1;
+                if failures >= 2 {
+                    return SessionEnd::Lost;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::{api::EdgeApi, config::Configuration, session::load_session};
+
+pub async fn status(cfg: &Configuration, session_path: &Path) -> Result<()> {
+    let session = load_session(session_path)?;
+    let tunnels = EdgeApi::new(cfg)?.list_edges(&session).await?;
+
+    if tunnels.is_empty() {
+        println!("no active tunnels");
+        return Ok(());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    println!(
+        "{:<20} {:<24} {:<24} {:<10} {:<10} {:>8} {:>8} {:>6} {:>8}",
+        "NAME", "PUBLIC", "TARGET", "PROTOCOL", "MODE", "ACTIVE", "TOTAL", "IDLE", "UPTIME"
+    );
+
+    for tunnel in tunnels {
+        println!(
+            "{:<20} {:<24} {:<24} {:<10} {:<10} {:>8} {:>8} {:>6} {:>7}s",
+            tunnel.name,
+            format!("{}:{}", cfg.edge_ip, tunnel.port),
+            tunnel.target,
+            format!("{:?}", tunnel.protocol),
+            format!("{:?}", tunnel.mode),
+            tunnel.connections.active,
+            tunnel.connections.total,
+            tunnel.idle_workers,
+            now.saturating_sub(tunnel.created_at),
+        );
+    }
+
+    Ok(())
+}
@@ -2,82 +2,114 @@ use std::{
     collections::HashMap,
     fs::read_to_string,
     net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
 };
 
 use anyhow::{anyhow, Result};
+use clap::ValueEnum;
 use log::info;
 use serde::{Deserialize, Serialize};
 use toml::from_str;
 
-const CONFIG_PATH: &str = if cfg!(debug_assertions) {
-    "client/config.toml"
-} else {
-    "config.toml"
-};
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
 
-#[derive(Deserialize, Serialize)]
+// Everything defaults to empty, as the connection settings can also come
+// from the command line or the environment.
+#[derive(Deserialize, Serialize, Default)]
 pub struct Configuration {
+    #[serde(default)]
     pub secret_key: String,
+    #[serde(default)]
     pub edge: String,
+    #[serde(default)]
     pub edge_ip: String,
+    #[serde(default)]
     pub idle_workers: usize,
+    /// Port of the local inspector, disabled if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inspector: Option<u16>,
+    /// Client certificate used in place of the secret key, and the CA the
+    /// edge's certificate is checked against.
+    #[serde(default, skip_serializing_if = "TlsConfig::is_empty")]
+    pub tls: TlsConfig,
+    #[serde(default)]
     pub tunnels: HashMap<String, Tunnel>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct TlsConfig {
+    /// PEM client certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+    /// PEM private key of the client certificate, in PKCS#8 format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    /// PEM CA certificates trusted on top of the system ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    fn is_empty(&self) -> bool {
+        self.cert.is_none() && self.key.is_none() && self.ca.is_none()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct Tunnel {
     pub target: String,
     pub protocol: Protocol,
     pub mode: Mode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, ValueEnum, Debug, Clone, PartialEq, Eq)]
+#[value(rename_all = "verbatim")]
 pub enum Protocol {
     Tcp,
     HAProxyV1,
     HAProxyV2,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, ValueEnum, Debug, Clone, PartialEq, Eq)]
+#[value(rename_all = "verbatim")]
 pub enum Mode {
     Reverse,
     HolePunch,
 }
 
-pub fn load_config() -> Result<Configuration> {
-    let file = read_to_string(CONFIG_PATH)?;
+pub fn load_config(path: &Path) -> Result<Configuration> {
+    let file = read_to_string(path)?;
     let mut config: Configuration = from_str(&file)?;
 
     for (id, tunnel) in config.tunnels.iter_mut() {
         // we have to resolve the target to an IP address
-        let target = match tunnel.target.parse::<SocketAddr>() {
-            Ok(target) => target,
-            Err(_) => {
-                let server = tunnel
-                    .target
-                    .to_socket_addrs()
-                    .expect("unable to resolve target")
-                    .collect::<Vec<SocketAddr>>();
-
-                let target = server
-                    .iter()
-                    .find(|&addr| addr.is_ipv4())
-                    .or_else(|| server.first())
-                    .ok_or_else(|| {
-                        anyhow!(
-                            "unable to resolve target {} for tunnel {}",
-                            tunnel.target,
-                            id
-                        )
-                    })?;
-
-                info!("resolved target {} to {}", tunnel.target, target);
-                *target
-            }
-        };
+        let target = resolve_target(&tunnel.target).map_err(|e| anyhow!("tunnel {id}: {e}"))?;
 
         tunnel.target = target.to_string();
     }
 
     Ok(config)
 }
+
+/// Resolves a tunnel target to a socket address, preferring IPv4.
+pub fn resolve_target(target: &str) -> Result<SocketAddr> {
+    if let Ok(target) = target.parse::<SocketAddr>() {
+        return Ok(target);
+    }
+
+    let server = target
+        .to_socket_addrs()
+        .map_err(|e| anyhow!("unable to resolve target {target}: {e}"))?
+        .collect::<Vec<SocketAddr>>();
+
+    let resolved = server
+        .iter()
+        .find(|&addr| addr.is_ipv4())
+        .or_else(|| server.first())
+        .ok_or_else(|| anyhow!("unable to resolve target {target}"))?;
+
+    info!("resolved target {} to {}", target, resolved);
+    Ok(*resolved)
+}
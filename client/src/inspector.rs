@@ -0,0 +1,208 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    get,
+    http::header::ContentType,
+    web::{Data, Json},
+    App, HttpResponse, HttpServer, Responder, Result,
+};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    api::{EdgeApi, Session},
+    config::{Configuration, Mode, Protocol, Tunnel},
+};
+
+/// Number of HolePunch connections kept for display.
+const RECENT_CONNECTIONS: usize = 50;
+
+/// What the client is currently doing, shared with the local inspector.
+#[derive(Clone, Default)]
+pub struct Inspector(Arc<Mutex<InspectorState>>);
+
+#[derive(Default, Serialize)]
+struct InspectorState {
+    state: ClientState,
+    #[serde(skip)]
+    session: Option<Session>,
+    tunnels: BTreeMap<String, TunnelState>,
+    /// Workers connected to the edge and waiting for a visitor.
+    idle_workers: usize,
+    /// Latest visitors handed to a HolePunch worker, newest first.
+    recent_connections: VecDeque<Connection>,
+}
+
+#[derive(Default, Serialize, Clone, Copy)]
+enum ClientState {
+    #[default]
+    Connecting,
+    Online,
+    Reconnecting,
+}
+
+#[derive(Serialize)]
+struct TunnelState {
+    target: String,
+    protocol: Protocol,
+    mode: Mode,
+    /// Address visitors use, known once the edge created the tunnel.
+    public_addr: Option<String>,
+    online: bool,
+}
+
+#[derive(Serialize)]
+struct Connection {
+    /// Unix timestamp of the handoff.
+    at: u64,
+    worker: usize,
+    target: String,
+    /// HolePunch tunnels relaying to that target, workers are not tied to one tunnel.
+    tunnels: Vec<String>,
+}
+
+/// Counts a worker as idle for as long as it is alive.
+pub struct IdleWorker(Inspector);
+
+impl Drop for IdleWorker {
+    fn drop(&mut self) {
+        self.0.update(|state| state.idle_workers -= 1);
+    }
+}
+
+impl Inspector {
+    pub fn new(cfg: &Configuration) -> Self {
+        let inspector = Self::default();
+        inspector.set_tunnels(&cfg.tunnels);
+        inspector
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut InspectorState) -> T) -> T {
+        let mut state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut state)
+    }
+
+    /// Replaces the configured tunnels. Tunnels that are kept stay online.
+    pub fn set_tunnels(&self, tunnels: &HashMap<String, Tunnel>) {
+        self.update(|state| {
+            state.tunnels = tunnels
+                .iter()
+                .map(|(name, tunnel)| {
+                    let previous = state.tunnels.remove(name);
+
+                    let tunnel = TunnelState {
+                        target: tunnel.target.clone(),
+                        protocol: tunnel.protocol.clone(),
+                        mode: tunnel.mode.clone(),
+                        public_addr: previous.as_ref().and_then(|t| t.public_addr.clone()),
+                        online: previous.is_some_and(|t| t.online),
+                    };
+
+                    (name.clone(), tunnel)
+                })
+                .collect();
+        });
+    }
+
+    pub fn connected(&self, session: &Session) {
+        self.update(|state| {
+            state.state = ClientState::Online;
+            state.session = Some(session.clone());
+        });
+    }
+
+    pub fn tunnel_created(&self, name: &str, public_addr: String) {
+        self.update(|state| {
+            if let Some(tunnel) = state.tunnels.get_mut(name) {
+                tunnel.public_addr = Some(public_addr);
+                tunnel.online = true;
+            }
+        });
+    }
+
+    pub fn disconnected(&self) {
+        self.update(|state| {
+            state.state = ClientState::Reconnecting;
+            state.session = None;
+            state.tunnels.values_mut().for_each(|t| t.online = false);
+        });
+    }
+
+    pub fn worker_idle(&self) -> IdleWorker {
+        self.update(|state| state.idle_workers += 1);
+        IdleWorker(self.clone())
+    }
+
+    pub fn record_connection(&self, worker: usize, target: &str) {
+        self.update(|state| {
+            let tunnels = state
+                .tunnels
+                .iter()
+                .filter(|(_, t)| matches!(t.mode, Mode::HolePunch) && t.target == target)
+                .map(|(name, _)| name.clone())
+                .collect();
+
+            state.recent_connections.push_front(Connection {
+                at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+                worker,
+                target: target.to_string(),
+                tunnels,
+            });
+            state.recent_connections.truncate(RECENT_CONNECTIONS);
+        });
+    }
+}
+
+#[get("/")]
+async fn page() -> Result<impl Responder> {
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(include_str!("inspector.html")))
+}
+
+#[get("/api/status")]
+async fn status(inspector: Data<Inspector>, api: Data<EdgeApi>) -> Result<impl Responder> {
+    let (mut value, session) = inspector.update(|state| (json!(state), state.session.clone()));
+
+    // The edge knows about every visitor, not just the HolePunch ones.
+    if let Some(session) = session {
+        match api.list_edges(&session).await {
+            Ok(tunnels) => {
+                for tunnel in tunnels {
+                    value["tunnels"][&tunnel.name]["connections"] = json!({
+                        "active": tunnel.connections.active,
+                        "total": tunnel.connections.total,
+                    });
+                }
+            }
+            Err(e) => warn!("failed to fetch tunnel stats from edge: {e}"),
+        }
+    }
+
+    Ok(Json(value))
+}
+
+/// Serves the inspector on localhost only, it is meant for whoever runs the client.
+pub async fn serve_inspector(api: EdgeApi, inspector: Inspector, port: u16) -> std::io::Result<()> {
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(Data::new(inspector.clone()))
+            .app_data(Data::new(api.clone()))
+            .service(page)
+            .service(status)
+    })
+    .workers(1)
+    .bind(("127.0.0.1", port))?;
+
+    info!("inspector listening on http://127.0.0.1:{port}");
+
+    server.run().await
+}
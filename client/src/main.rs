@@ -1,74 +1,68 @@
-use anyhow::{bail, Result};
-use config::load_config;
-use log::info;
-use tokio::{signal::ctrl_c, sync::oneshot::channel};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::Parser;
+use cli::{check_config::check_config, down::down, run::run, status::status, Cli, Commands};
+use config::{load_config, Configuration, DEFAULT_CONFIG_PATH};
+use logging::init_logging;
+use session::session_path;
 
 pub mod api;
+pub mod backoff;
+pub mod cli;
 pub mod config;
+pub mod inspector;
+pub mod session;
+pub mod tls;
+pub mod watcher;
 pub mod worker;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    init_logging();
-
-    let cfg = load_config()?;
-
-    if cfg.tunnels.is_empty() {
-        bail!("no tunnels defined in config.toml");
-    } else {
-        info!("booting with {} tunnels...", cfg.tunnels.len());
-    }
-
-    info!("contacting edge server at {}...", cfg.edge);
-
-    if !api::check_authorization(&cfg).await? {
-        bail!("failed to authorize with edge server");
-    }
-
-    let worker_port = api::connect(&cfg).await?;
-
-    info!(
-        "connecting to edge worker server at port {}...",
-        worker_port
-    );
-
-    let (tx, rx) = channel();
-    worker::start_workers(&cfg, worker_port, rx).await?;
-
-    for (id, tunnel) in &cfg.tunnels {
-        let (status, target) = api::create_edge(&cfg, id.clone(), tunnel).await?;
-
-        if status == "ok" {
-            info!(
-                "tunnel {id} (proto={:?}, mode={:?}) created successfully -> {}",
-                tunnel.protocol, tunnel.mode, target
-            );
-        } else {
-            bail!(
-                "failed to create tunnel {id} (proto={:?}, mode={:?}), status: {status}",
-                tunnel.protocol,
-                tunnel.mode
-            );
+    let mut cli = Cli::parse();
+
+    init_logging(cli.log_format);
+
+    let config_path = cli
+        .config
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+    let session_path = session_path(&config_path);
+
+    match cli.command.take().unwrap_or(Commands::Run {}) {
+        // Checked before loading, which would fail on the first problem.
+        Commands::CheckConfig {} => check_config(&config_path, &cli.connection),
+        Commands::Run {} => {
+            let cfg = load(&cli, &config_path)?;
+            let watched = config_path.exists().then_some(config_path.as_path());
+            run(Box::leak(Box::new(cfg)), &session_path, watched).await
+        }
+        Commands::Tcp {
+            port,
+            name,
+            protocol,
+            mode,
+        } => {
+            let mut cfg = load(&cli, &config_path)?;
+            cli::ad_hoc_tunnel(&mut cfg, port, name, protocol, mode);
+            run(Box::leak(Box::new(cfg)), &session_path, None).await
         }
+        Commands::Status {} => status(&load(&cli, &config_path)?, &session_path).await,
+        Commands::Down {} => down(&load(&cli, &config_path)?, &session_path).await,
     }
-
-    ctrl_c().await?;
-
-    info!("shutting down...");
-
-    tx.send(()).unwrap();
-    let response = api::goodbye(&cfg).await?;
-    info!("edge server said: {response}");
-
-    Ok(())
 }
 
-fn init_logging() {
-    if cfg!(debug_assertions) {
-        std::env::set_var("RUST_LOG", "debug");
-    } else if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info");
-    }
+/// Loads the configuration and applies the command line overrides to it.
+fn load(cli: &Cli, config_path: &Path) -> Result<Configuration> {
+    // Without an explicit --config, a missing file is fine as long as the
+    // connection settings are passed on the command line.
+    let mut cfg = if cli.config.is_none() && !config_path.exists() {
+        Configuration::default()
+    } else {
+        load_config(config_path)?
+    };
+
+    cli.connection.apply(&mut cfg)?;
 
-    env_logger::init();
+    Ok(cfg)
 }
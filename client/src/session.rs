@@ -0,0 +1,45 @@
+use std::{
+    fs::{read_to_string, remove_file, write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::api::Session;
+
+/// The running client keeps its session id next to its config file, so
+/// `status` and `down` can act on it from another process. When running
+/// without a config file, the current directory is used instead.
+pub fn session_path(config_path: &Path) -> PathBuf {
+    let directory_exists = config_path
+        .parent()
+        .is_none_or(|parent| parent.as_os_str().is_empty() || parent.is_dir());
+
+    let mut path = if directory_exists {
+        config_path.as_os_str().to_owned()
+    } else {
+        config_path.file_name().unwrap_or_default().to_owned()
+    };
+
+    path.push(".session");
+    PathBuf::from(path)
+}
+
+pub fn save_session(path: &Path, session: &Session) -> Result<()> {
+    write(path, &session.id)?;
+    Ok(())
+}
+
+pub fn load_session(path: &Path) -> Result<Session> {
+    let id = read_to_string(path)
+        .map_err(|_| anyhow!("no running client found ({} is missing)", path.display()))?;
+
+    Ok(Session {
+        id: id.trim().to_string(),
+        lease: 0,
+    })
+}
+
+pub fn remove_session(path: &Path) {
+    let _ = remove_file(path);
+}
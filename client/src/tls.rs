@@ -0,0 +1,81 @@
+use std::{fs, path::Path, pin::Pin};
+
+use anyhow::{anyhow, Result};
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod};
+use reqwest::{Certificate, Client, Identity, Url};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+use crate::config::Configuration;
+
+/// Connects HolePunch workers to an edge serving them over TLS.
+pub struct WorkerTls {
+    connector: SslConnector,
+    /// Host of the edge url, which the edge's certificate has to be valid for.
+    domain: String,
+}
+
+impl WorkerTls {
+    pub fn new(cfg: &Configuration) -> Result<Self> {
+        let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+
+        if let (Some(cert), Some(key)) = (&cfg.tls.cert, &cfg.tls.key) {
+            builder
+                .set_certificate_chain_file(cert)
+                .map_err(|e| anyhow!("unable to load certificate {}: {e}", cert.display()))?;
+            builder
+                .set_private_key_file(key, SslFiletype::PEM)
+                .map_err(|e| anyhow!("unable to load private key {}: {e}", key.display()))?;
+        }
+
+        if let Some(ca) = &cfg.tls.ca {
+            builder
+                .set_ca_file(ca)
+                .map_err(|e| anyhow!("unable to load CA {}: {e}", ca.display()))?;
+        }
+
+        let domain = Url::parse(&cfg.edge)?
+            .host_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("edge url {} has no host", cfg.edge))?;
+
+        Ok(Self {
+            connector: builder.build(),
+            domain,
+        })
+    }
+
+    pub async fn connect(&self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        let ssl = self.connector.configure()?.into_ssl(&self.domain)?;
+        let mut stream = SslStream::new(ssl, stream)?;
+        Pin::new(&mut stream).connect().await?;
+
+        Ok(stream)
+    }
+}
+
+/// An HTTP client presenting the client certificate, if there is one, and
+/// trusting the configured CA.
+pub fn http_client(cfg: &Configuration) -> Result<Client> {
+    let mut builder = Client::builder();
+
+    if let (Some(cert), Some(key)) = (&cfg.tls.cert, &cfg.tls.key) {
+        builder = builder.identity(
+            Identity::from_pkcs8_pem(&read_pem(cert)?, &read_pem(key)?)
+                .map_err(|e| anyhow!("unable to load client certificate: {e}"))?,
+        );
+    }
+
+    if let Some(ca) = &cfg.tls.ca {
+        builder = builder.add_root_certificate(
+            Certificate::from_pem(&read_pem(ca)?)
+                .map_err(|e| anyhow!("unable to load CA {}: {e}", ca.display()))?,
+        );
+    }
+
+    Ok(builder.build()?)
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| anyhow!("unable to read {}: {e}", path.display()))
+}
@@ -0,0 +1,71 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use log::{error, info};
+use tokio::time::sleep;
+
+use crate::config::{load_config, Configuration};
+
+/// How often the configuration file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Notices changes to the configuration file by polling its modification time.
+pub struct ConfigWatcher {
+    /// Unset when there is no file to watch, e.g. for `client tcp`.
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: Option<&Path>) -> Self {
+        let path = path.map(Path::to_path_buf);
+        let modified = path.as_deref().and_then(modified);
+
+        Self { path, modified }
+    }
+
+    /// Returns the new configuration if the file changed since the last call.
+    /// A file that fails to load is reported and otherwise ignored, so a
+    /// half-saved edit never tears down running tunnels.
+    pub fn check(&mut self) -> Option<Configuration> {
+        let path = self.path.as_deref()?;
+        let modified = modified(path);
+
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        match load_config(path) {
+            Ok(cfg) => {
+                info!(
+                    "configuration file {} changed, reloading...",
+                    path.display()
+                );
+                Some(cfg)
+            }
+            Err(e) => {
+                error!("failed to reload {}: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    /// Waits until the file changes and returns the new configuration.
+    pub async fn changed(&mut self) -> Configuration {
+        loop {
+            sleep(POLL_INTERVAL).await;
+
+            if let Some(cfg) = self.check() {
+                return cfg;
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
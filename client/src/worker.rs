@@ -1,26 +1,52 @@
-use std::sync::{
-    atomic::{AtomicBool, AtomicUsize, Ordering},
-    Arc,
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use anyhow::{bail, Result};
 use log::{error, info};
 use tokio::{
-    io::{copy, AsyncReadExt, AsyncWriteExt},
+    io::{copy, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
     sync::oneshot::Receiver,
+    time::sleep,
 };
 
-use crate::config::Configuration;
+use crate::{
+    api::{Session, WorkerServer},
+    backoff::Backoff,
+    config::Configuration,
+    inspector::Inspector,
+    tls::WorkerTls,
+};
+
+pub async fn start_workers(
+    cfg: &Configuration,
+    session: &Session,
+    server: WorkerServer,
+    close: Receiver<()>,
+    inspector: Inspector,
+) -> Result<()> {
+    let tls = if server.tls {
+        Some(Arc::new(WorkerTls::new(cfg)?))
+    } else if cfg.secret_key.is_empty() && cfg.idle_workers > 0 {
+        // The certificate can't be presented, and the edge would count the
+        // empty key as a failed authentication of this host.
+        bail!("the edge worker port doesn't use TLS, so workers need a secret key to authenticate");
+    } else {
+        None
+    };
 
-pub async fn start_workers(cfg: &Configuration, port: u16, close: Receiver<()>) -> Result<()> {
     let closed = AtomicBool::new(false);
     let closed = Arc::new(closed);
 
     {
         let closed = closed.clone();
         tokio::spawn(async move {
-            close.await.unwrap();
+            let _ = close.await;
             closed.store(true, Ordering::Relaxed);
         });
     }
@@ -31,10 +57,15 @@ pub async fn start_workers(cfg: &Configuration, port: u16, close: Receiver<()>)
     for _ in 0..cfg.idle_workers {
         let ip = cfg.edge_ip.clone();
         let secret = cfg.secret_key.clone();
+        let session = session.id.clone();
 
+        let tls = tls.clone();
         let closed = closed.clone();
         let worker_id = worker_id.clone();
+        let inspector = inspector.clone();
         tokio::spawn(async move {
+            let mut backoff = Backoff::new(Duration::from_millis(250), Duration::from_secs(30));
+
             loop {
                 if closed.load(Ordering::Relaxed) {
                     break;
@@ -43,11 +74,22 @@ pub async fn start_workers(cfg: &Configuration, port: u16, close: Receiver<()>)
                 let id = worker_id.fetch_add(1, Ordering::Relaxed);
 
                 info!("starting worker #{id}...");
-                match run_worker(id, ip.clone(), port, secret.clone()).await {
-                    Ok(_) => {}
+                match run_worker(
+                    id,
+                    ip.clone(),
+                    server.port,
+                    tls.as_deref(),
+                    secret.clone(),
+                    session.clone(),
+                    &inspector,
+                )
+                .await
+                {
+                    Ok(_) => backoff.reset(),
                     Err(e) => {
                         if !closed.load(Ordering::Relaxed) {
                             error!("worker {id} failed: {e}");
+                            sleep(backoff.next_delay()).await;
                         }
                     }
                 }
@@ -58,12 +100,41 @@ pub async fn start_workers(cfg: &Configuration, port: u16, close: Receiver<()>)
     Ok(())
 }
 
-async fn run_worker(id: usize, ip: String, port: u16, secret: String) -> Result<()> {
-    let mut stream = TcpStream::connect((ip, port)).await?;
+async fn run_worker(
+    id: usize,
+    ip: String,
+    port: u16,
+    tls: Option<&WorkerTls>,
+    secret: String,
+    session: String,
+    inspector: &Inspector,
+) -> Result<()> {
+    let stream = TcpStream::connect((ip, port)).await?;
+
+    match tls {
+        Some(tls) => serve(id, tls.connect(stream).await?, secret, session, inspector).await,
+        None => serve(id, stream, secret, session, inspector).await,
+    }
+}
+
+/// Waits on an established worker connection for the edge to hand it a
+/// visitor, and relays it to the target. Without a secret key, the edge
+/// identifies the worker by its client certificate.
+async fn serve(
+    id: usize,
+    mut stream: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    secret: String,
+    session: String,
+    inspector: &Inspector,
+) -> Result<()> {
+    let idle = inspector.worker_idle();
 
     // Send authorization
     stream.write_all(secret.as_bytes()).await?;
     stream.write_all(b"\n").await?;
+    stream.write_all(session.as_bytes()).await?;
+    stream.write_all(b"\n").await?;
+    stream.flush().await?;
 
     // Wait for signal
     let mut b = [0; 1];
@@ -92,12 +163,15 @@ async fn run_worker(id: usize, ip: String, port: u16, secret: String) -> Result<
 
     info!("worker #{id} is being used to proxy to {target}");
 
+    drop(idle);
+    inspector.record_connection(id, &target);
+
     // We create a stream
     let server = TcpStream::connect(target).await?;
 
     // We proxy
     let (mut server_reader, mut server_writer) = server.into_split();
-    let (mut client_reader, mut client_writer) = stream.into_split();
+    let (mut client_reader, mut client_writer) = split(stream);
 
     tokio::spawn(async move {
         if let Err(e) = copy(&mut server_reader, &mut client_writer).await {
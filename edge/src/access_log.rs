@@ -0,0 +1,149 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::Result;
+use log::{error, info};
+use serde::Serialize;
+
+use crate::{
+    config::{with_suffix, AccessLogConfig},
+    listener::proxy::Mode,
+};
+
+/// Set when access entries go to their own file, otherwise they are written
+/// to the regular log under the `access` target.
+static ACCESS_LOG: OnceLock<Mutex<RotatingFile>> = OnceLock::new();
+
+/// One relayed (or refused) visitor connection.
+#[derive(Serialize)]
+pub struct AccessEntry<'a> {
+    /// Unix timestamp of the moment the connection ended.
+    pub timestamp: u64,
+    pub tunnel: &'a str,
+    pub user: &'a str,
+    pub visitor: SocketAddr,
+    pub mode: &'a Mode,
+    pub duration_ms: u64,
+    /// Bytes sent by the visitor to the target.
+    pub bytes_in: u64,
+    /// Bytes sent by the target back to the visitor.
+    pub bytes_out: u64,
+    pub close_reason: &'a str,
+}
+
+impl fmt::Display for AccessEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tunnel={} user={} visitor={} mode={:?} duration={}ms in={} out={} reason=\"{}\"",
+            self.tunnel,
+            self.user,
+            self.visitor,
+            self.mode,
+            self.duration_ms,
+            self.bytes_in,
+            self.bytes_out,
+            self.close_reason
+        )
+    }
+}
+
+/// Sends access entries to their own file instead of the regular log.
+pub fn init_access_log(config: &AccessLogConfig) -> Result<()> {
+    let file = RotatingFile::open(config)?;
+    let _ = ACCESS_LOG.set(Mutex::new(file));
+
+    info!("writing access log to {}", config.path.display());
+    Ok(())
+}
+
+pub fn record(entry: &AccessEntry) {
+    let Some(file) = ACCESS_LOG.get() else {
+        info!(target: "access", "{entry}");
+        return;
+    };
+
+    let mut line = match serde_json::to_vec(entry) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("failed to serialize access log entry: {e}");
+            return;
+        }
+    };
+    line.push(b'\n');
+
+    let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = file.write_line(&line) {
+        error!("failed to write access log: {e}");
+    }
+}
+
+/// A file that is moved to `<path>.1` once it grows past its maximum size,
+/// shifting older files up to `<path>.<keep>`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(config: &AccessLogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: config.path.clone(),
+            file,
+            size,
+            max_size: config.max_size,
+            keep: config.keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..self.keep).rev() {
+            let from = with_suffix(&self.path, &i.to_string());
+            let to = with_suffix(&self.path, &(i + 1).to_string());
+
+            match fs::rename(from, to) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        if self.keep > 0 {
+            fs::rename(&self.path, with_suffix(&self.path, "1"))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    net::IpAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
+
+use actix_web::{
+    delete,
+    error::{ErrorInternalServerError, ErrorNotFound},
+    get,
+    http::header::ContentType,
+    web::{Data, Json, Path, Query},
+    HttpRequest, HttpResponse, Responder, Result,
+};
+use actix_web_httpauth::{
+    extractors::{basic::BasicAuth, AuthenticationError},
+    headers::www_authenticate::basic::Basic,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use crate::{
+    api::{edge::describe_tunnel, source_ip},
+    audit::{self, AuditEvent},
+    auth::Credentials,
+    config::Scope,
+    state::State,
+};
+
+/// Browsers can't send bearer tokens by themselves, so the dashboard uses
+/// basic auth with an admin key as the password. The user name is ignored.
+fn check_admin(auth: &BasicAuth, state: &mut State) -> Result<()> {
+    match state.authenticate(&admin_key(auth), Some(Scope::Admin)) {
+        Some(_) => Ok(()),
+        None => Err(AuthenticationError::new(Basic::with_realm("edge")).into()),
+    }
+}
+
+fn admin_key(auth: &BasicAuth) -> Credentials {
+    Credentials::Key(auth.password().unwrap_or_default().to_string())
+}
+
+#[get("/admin")]
+pub async fn dashboard(auth: BasicAuth, data: Data<Arc<Mutex<State>>>) -> Result<impl Responder> {
+    check_admin(&auth, &mut *data.lock().await)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(include_str!("dashboard.html")))
+}
+
+#[get("/api/v1/admin/overview")]
+pub async fn overview(auth: BasicAuth, data: Data<Arc<Mutex<State>>>) -> Result<impl Responder> {
+    let mut state = data.lock().await;
+
+    check_admin(&auth, &mut state)?;
+
+    let now = Instant::now();
+
+    let mut users: Vec<_> = state
+        .secrets
+        .values()
+        .map(|secret| {
+            let sessions: Vec<_> = secret
+                .sessions
+                .iter()
+                .map(|(id, session)| {
+                    json!({
+                        "id": id,
+                        "client_addr": session.client_addr,
+                        "closed": session.closed,
+                        "idle_workers": session.workers.len(),
+                        "lease_expires_in": session.lease_expires.saturating_duration_since(now).as_secs(),
+                    })
+                })
+                .collect();
+
+            let tunnels: Vec<_> = secret
+                .active_tunnels
+                .iter()
+                .map(|tunnel| {
+                    let mut value = describe_tunnel(secret, tunnel);
+                    value["session"] = json!(tunnel.session);
+                    value
+                })
+                .collect();
+
+            json!({
+                "name": secret.name,
+                "max_tunnels": secret.max_tunnels,
+                "sessions": sessions,
+                "tunnels": tunnels,
+            })
+        })
+        .collect();
+
+    users.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+
+    let tunnels = state
+        .secrets
+        .values()
+        .flat_map(|secret| secret.active_tunnels.iter());
+
+    Ok(Json(json!({
+        "status": "ok",
+        "users": users,
+        "totals": {
+            "active_connections": tunnels.clone().map(|t| t.stats.active_connections.load(Ordering::Relaxed)).sum::<usize>(),
+            "bytes_in": tunnels.clone().map(|t| t.stats.bytes_in.load(Ordering::Relaxed)).sum::<u64>(),
+            "bytes_out": tunnels.map(|t| t.stats.bytes_out.load(Ordering::Relaxed)).sum::<u64>(),
+        },
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    user: Option<String>,
+    action: Option<String>,
+    /// Unix timestamp of the oldest record to return.
+    #[serde(default)]
+    since: u64,
+    #[serde(default = "default_audit_limit")]
+    limit: usize,
+}
+
+fn default_audit_limit() -> usize {
+    100
+}
+
+/// The latest audit records, oldest first, and whether their chain is intact.
+#[get("/api/v1/admin/audit")]
+pub async fn audit_log(
+    auth: BasicAuth,
+    data: Data<Arc<Mutex<State>>>,
+    query: Query<AuditQuery>,
+) -> Result<impl Responder> {
+    check_admin(&auth, &mut *data.lock().await)?;
+
+    let (records, broken) = audit::query(
+        query.user.as_deref(),
+        query.action.as_deref(),
+        query.since,
+        query.limit,
+    )
+    .map_err(ErrorInternalServerError)?;
+
+    Ok(Json(json!({
+        "status": "ok",
+        "intact": broken.is_none(),
+        "problem": broken,
+        "records": records,
+    })))
+}
+
+/// Source IPs with recent failed authentications, and whether they are banned.
+#[get("/api/v1/admin/bans")]
+pub async fn list_bans(auth: BasicAuth, data: Data<Arc<Mutex<State>>>) -> Result<impl Responder> {
+    let state = &mut *data.lock().await;
+    check_admin(&auth, state)?;
+
+    Ok(Json(json!({
+        "status": "ok",
+        "bans": state.auth_failures.list(),
+    })))
+}
+
+/// Lifts a source IP's ban and forgets its failures.
+#[delete("/api/v1/admin/bans/{ip}")]
+pub async fn lift_ban(
+    req: HttpRequest,
+    auth: BasicAuth,
+    data: Data<Arc<Mutex<State>>>,
+    ip: Path<IpAddr>,
+) -> Result<impl Responder> {
+    let state = &mut *data.lock().await;
+    check_admin(&auth, state)?;
+
+    let lifted = state.auth_failures.unban(*ip);
+    let identity = state.identify(&admin_key(&auth));
+
+    audit::record(AuditEvent {
+        user: identity.as_ref().map(|(user, _)| user.as_str()),
+        key: identity.as_ref().map(|(_, key)| key.as_str()),
+        source: &source_ip(&req),
+        action: "auth.unban",
+        params: json!({"ip": *ip}),
+        outcome: if lifted { "ok" } else { "not banned" },
+    });
+
+    if lifted {
+        Ok(Json(json!({"status": "ok"})))
+    } else {
+        Err(ErrorNotFound(Json(json!({"status": "not banned"}))))
+    }
+}
@@ -1,70 +1,203 @@
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
 
 use actix_web::{
     delete,
-    error::{ErrorBadRequest, ErrorForbidden, ErrorTooManyRequests},
-    post,
-    web::{Data, Form, Json},
-    Responder, Result,
+    error::{ErrorBadRequest, ErrorConflict, ErrorForbidden, ErrorNotFound, ErrorTooManyRequests},
+    get, patch, post,
+    web::{Data, Form, Json, Path},
+    HttpRequest, Responder, Result,
 };
-use actix_web_httpauth::extractors::bearer::BearerAuth;
+use log::error;
 
-use serde::Deserialize;
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use tokio::sync::{oneshot, Mutex};
 
 use crate::{
+    auth::{now, Credentials},
+    config::Scope,
     listener::{
-        proxy::{Mode, Protocol},
+        proxy::{Mode, Protocol, TunnelBind, TunnelSpec},
         ListenerMessage,
     },
-    state::{State, Tunnel},
+    state::{Reservation, Secret, State, Tunnel, TunnelStats},
 };
 
-#[derive(Deserialize)]
+use super::{audit_action, check_accepting, session::ClientSession};
+
+#[derive(Deserialize, Serialize)]
 pub struct CreateRequestData {
     name: String,
     target: String,
     protocol: Protocol,
     mode: Mode,
+    /// Port the client would like to keep, e.g. after reconnecting.
+    port: Option<u16>,
+    max_connections: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UpdateRequestData {
+    target: Option<String>,
+    protocol: Option<Protocol>,
+    mode: Option<Mode>,
+    /// New connection limit, `0` removes it.
+    max_connections: Option<usize>,
 }
 
 #[derive(Deserialize)]
 pub struct DeleteRequestData {
-    target: String,
+    name: String,
+}
+
+#[get("/api/v1/edge")]
+pub async fn list_edges(
+    auth: Credentials,
+    session: Option<ClientSession>,
+    data: Data<Arc<Mutex<State>>>,
+) -> Result<impl Responder> {
+    let mut state = data.lock().await;
+
+    let secret = state
+        .authenticate(&auth, Some(Scope::Read))
+        .ok_or_else(|| ErrorForbidden(Json(json!({"status": "forbidden"}))))?;
+
+    let tunnels: Vec<_> = secret
+        .active_tunnels
+        .iter()
+        .filter(|t| session.as_ref().is_none_or(|s| s.0 == t.session))
+        .map(|t| describe_tunnel(secret, t))
+        .collect();
+
+    Ok(Json(json!({"status": "ok", "tunnels": tunnels})))
+}
+
+#[get("/api/v1/edge/{name}")]
+pub async fn get_edge(
+    auth: Credentials,
+    data: Data<Arc<Mutex<State>>>,
+    name: Path<String>,
+) -> Result<impl Responder> {
+    let mut state = data.lock().await;
+
+    let secret = state
+        .authenticate(&auth, Some(Scope::Read))
+        .ok_or_else(|| ErrorForbidden(Json(json!({"status": "forbidden"}))))?;
+
+    let tunnel = secret
+        .active_tunnels
+        .iter()
+        .find(|t| t.name == *name)
+        .ok_or_else(|| ErrorNotFound(Json(json!({"status": "no such tunnel"}))))?;
+
+    Ok(Json(
+        json!({"status": "ok", "tunnel": describe_tunnel(secret, tunnel)}),
+    ))
 }
 
 #[post("/api/v1/edge")]
 pub async fn create_edge(
-    auth: BearerAuth,
+    req: HttpRequest,
+    auth: Credentials,
+    session: ClientSession,
     data: Data<Arc<Mutex<State>>>,
     form: Form<CreateRequestData>,
 ) -> Result<impl Responder> {
+    let params = json!({"session": session.0, "tunnel": *form});
+    let result = create_tunnel(&auth, session, &data, form).await;
+
+    audit_action(&req, &auth, &data, "tunnel.create", params, &result).await;
+    result
+}
+
+async fn create_tunnel(
+    auth: &Credentials,
+    session: ClientSession,
+    data: &Data<Arc<Mutex<State>>>,
+    form: Form<CreateRequestData>,
+) -> Result<Json<Value>> {
     let mut state = data.lock().await;
 
+    check_accepting(&state)?;
+
     let listener_tx = state.listener_tx.clone();
+    let reserved_ports = state.reserved_ports();
 
     let secret = state
-        .secrets
-        .get_mut(auth.token())
+        .authenticate(auth, Some(Scope::Tunnels))
         .ok_or_else(|| ErrorForbidden(Json(json!({"status": "forbidden"}))))?;
 
-    if secret.max_tunnels <= secret.active_tunnels.len() {
+    session.check(secret)?;
+
+    let spec = TunnelSpec {
+        target: form.target.clone(),
+        protocol: form.protocol.clone(),
+        mode: form.mode.clone(),
+        max_connections: form.max_connections,
+    };
+
+    // Names are unique per user, so re-creating a tunnel hands it over to the
+    // calling session instead of opening a second one, as long as the session
+    // that created it is gone.
+    if let Some(index) = secret
+        .active_tunnels
+        .iter()
+        .position(|t| t.name == form.name)
+    {
+        let owner = &secret.active_tunnels[index].session;
+        let owner_alive = *owner != session.0
+            && secret
+                .sessions
+                .get(owner)
+                .is_some_and(|owner| !owner.closed);
+
+        if owner_alive {
+            return Err(ErrorConflict(Json(
+                json!({"status": "tunnel is used by another session"}),
+            )));
+        }
+
+        let tunnel = &mut secret.active_tunnels[index];
+
+        if tunnel.spec != spec {
+            return Err(ErrorConflict(Json(
+                json!({"status": "tunnel exists with a different spec"}),
+            )));
+        }
+
+        tunnel.session = session.0;
+
+        return Ok(Json(json!({"status": "ok", "port": tunnel.port})));
+    }
+
+    // Offline reservations hold on to their ports, so they count as well.
+    if !secret.reservations.contains_key(&form.name) && secret.max_tunnels <= secret.tunnel_count()
+    {
         return Err(ErrorTooManyRequests(Json(
             json!({"status": "too many tunnels"}),
         )));
     }
+    let stats = Arc::new(TunnelStats::new(secret.usage.clone()));
+
+    // A tunnel gets its reserved port back, and never takes another's.
+    let port = match secret.reservations.get(&form.name) {
+        Some(reservation) => Some(reservation.port),
+        None => form.port.filter(|port| !reserved_ports.contains(port)),
+    };
 
     let (tx, rx) = oneshot::channel();
 
     if listener_tx
         .send(ListenerMessage::Listen {
             reply: tx,
-            tunnel: form.target.clone(),
-            protocol: form.protocol.clone(),
-            mode: form.mode.clone(),
+            spec: spec.clone(),
             name: form.name.clone(),
-            secret: auth.token().to_string(),
+            user: secret.name.clone(),
+            bind: TunnelBind::Port {
+                port,
+                reserved: reserved_ports,
+            },
+            stats: stats.clone(),
         })
         .is_err()
     {
@@ -75,13 +208,26 @@ pub async fn create_edge(
 
     match rx.await {
         Ok(Some(port)) => {
+            secret.reservations.insert(
+                form.name.clone(),
+                Reservation {
+                    port,
+                    protocol: spec.protocol.clone(),
+                    mode: spec.mode.clone(),
+                },
+            );
+
             let tunnel = Tunnel {
                 name: form.name.clone(),
-                target: form.target.clone(),
+                spec,
                 port,
+                session: session.0,
+                created_at: now(),
+                stats,
             };
 
             secret.active_tunnels.push(tunnel);
+            state.save_reservations();
 
             Ok(Json(json!({"status": "ok", "port": port})))
         }
@@ -96,55 +242,194 @@ pub async fn create_edge(
     }
 }
 
+#[patch("/api/v1/edge/{name}")]
+pub async fn update_edge(
+    req: HttpRequest,
+    auth: Credentials,
+    session: ClientSession,
+    data: Data<Arc<Mutex<State>>>,
+    name: Path<String>,
+    form: Form<UpdateRequestData>,
+) -> Result<impl Responder> {
+    let params = json!({"session": session.0, "name": *name, "changes": *form});
+    let result = update_tunnel(&auth, session, &data, name, form).await;
+
+    audit_action(&req, &auth, &data, "tunnel.update", params, &result).await;
+    result
+}
+
+async fn update_tunnel(
+    auth: &Credentials,
+    session: ClientSession,
+    data: &Data<Arc<Mutex<State>>>,
+    name: Path<String>,
+    form: Form<UpdateRequestData>,
+) -> Result<Json<Value>> {
+    let mut state = data.lock().await;
+
+    check_accepting(&state)?;
+
+    let listener_tx = state.listener_tx.clone();
+
+    let secret = state
+        .authenticate(auth, Some(Scope::Tunnels))
+        .ok_or_else(|| ErrorForbidden(Json(json!({"status": "forbidden"}))))?;
+
+    session.check(secret)?;
+
+    let tunnel = secret
+        .active_tunnels
+        .iter_mut()
+        .find(|t| t.session == session.0 && t.name == *name)
+        .ok_or_else(|| ErrorNotFound(Json(json!({"status": "no such tunnel"}))))?;
+
+    let mut spec = tunnel.spec.clone();
+    let form = form.into_inner();
+
+    if let Some(target) = form.target {
+        spec.target = target;
+    }
+    if let Some(protocol) = form.protocol {
+        spec.protocol = protocol;
+    }
+    if let Some(mode) = form.mode {
+        spec.mode = mode;
+    }
+    if let Some(max_connections) = form.max_connections {
+        spec.max_connections = Some(max_connections).filter(|max| *max > 0);
+    }
+
+    if listener_tx
+        .send(ListenerMessage::Update {
+            port: tunnel.port,
+            spec: spec.clone(),
+        })
+        .is_err()
+    {
+        return Err(ErrorBadRequest(Json(
+            json!({"status": "failed to request tunnel update"}),
+        )));
+    }
+
+    let port = tunnel.port;
+
+    if let Some(reservation) = secret.reservations.get_mut(&tunnel.name) {
+        reservation.protocol = spec.protocol.clone();
+        reservation.mode = spec.mode.clone();
+    }
+
+    tunnel.spec = spec;
+    state.save_reservations();
+
+    Ok(Json(json!({"status": "ok", "port": port})))
+}
+
 #[delete("/api/v1/edge")]
 pub async fn delete_edge(
-    auth: BearerAuth,
+    req: HttpRequest,
+    auth: Credentials,
+    session: ClientSession,
     data: Data<Arc<Mutex<State>>>,
     form: Form<DeleteRequestData>,
 ) -> Result<impl Responder> {
+    let params = json!({"session": session.0, "name": form.name});
+    let result = delete_tunnel(&auth, session, &data, form).await;
+
+    audit_action(&req, &auth, &data, "tunnel.delete", params, &result).await;
+    result
+}
+
+async fn delete_tunnel(
+    auth: &Credentials,
+    session: ClientSession,
+    data: &Data<Arc<Mutex<State>>>,
+    form: Form<DeleteRequestData>,
+) -> Result<Json<Value>> {
     let mut state = data.lock().await;
 
     let listener_tx = state.listener_tx.clone();
 
     let secret = state
-        .secrets
-        .get_mut(auth.token())
+        .authenticate(auth, Some(Scope::Tunnels))
         .ok_or_else(|| ErrorForbidden(Json(json!({"status": "forbidden"}))))?;
 
+    session.check(secret)?;
+
+    let owned = |t: &Tunnel| t.session == session.0 && t.name == form.name;
+
     let tunnel = secret
         .active_tunnels
         .iter()
-        .find(|t| t.target == form.target)
+        .find(|t| owned(t))
         .ok_or_else(|| ErrorBadRequest(Json(json!({"status": "no such tunnel"}))))?;
 
     if let Err(e) = listener_tx.send(ListenerMessage::Stop { port: tunnel.port }) {
-        eprintln!("failed to send stop message: {}", e);
+        error!("failed to send stop message: {e}");
     }
 
-    secret.active_tunnels.retain(|t| t.target != form.target);
+    secret.active_tunnels.retain(|t| !owned(t));
+    secret.reservations.remove(&form.name);
+    state.save_reservations();
 
     Ok(Json(json!({"status": "ok"})))
 }
 
 #[delete("/api/v1/edge/all")]
 pub async fn delete_edges(
-    auth: BearerAuth,
+    req: HttpRequest,
+    auth: Credentials,
+    session: ClientSession,
     data: Data<Arc<Mutex<State>>>,
 ) -> Result<impl Responder> {
+    let params = json!({"session": session.0});
+    let result = delete_session_tunnels(&auth, session, &data).await;
+
+    audit_action(&req, &auth, &data, "tunnel.delete_all", params, &result).await;
+    result
+}
+
+async fn delete_session_tunnels(
+    auth: &Credentials,
+    session: ClientSession,
+    data: &Data<Arc<Mutex<State>>>,
+) -> Result<Json<Value>> {
     let mut state = data.lock().await;
 
     let listener_tx = state.listener_tx.clone();
 
     let secret = state
-        .secrets
-        .get_mut(auth.token())
+        .authenticate(auth, Some(Scope::Tunnels))
         .ok_or_else(|| ErrorForbidden(Json(json!({"status": "forbidden"}))))?;
 
-    for tunnel in secret.active_tunnels.drain(..) {
-        if let Err(e) = listener_tx.send(ListenerMessage::Stop { port: tunnel.port }) {
-            eprintln!("failed to send stop message: {}", e);
-        }
-    }
+    session.check(secret)?;
+    secret.release_session_tunnels(&session.0, &listener_tx);
+    state.save_reservations();
 
     Ok(Json(json!({"status": "ok"})))
 }
+
+pub fn describe_tunnel(secret: &Secret, tunnel: &Tunnel) -> Value {
+    let idle_workers = secret
+        .sessions
+        .get(&tunnel.session)
+        .map_or(0, |session| session.workers.len());
+
+    json!({
+        "name": tunnel.name,
+        "target": tunnel.spec.target,
+        "protocol": tunnel.spec.protocol,
+        "mode": tunnel.spec.mode,
+        "port": tunnel.port,
+        "max_connections": tunnel.spec.max_connections,
+        "created_at": tunnel.created_at,
+        "connections": {
+            "active": tunnel.stats.active_connections.load(Ordering::Relaxed),
+            "total": tunnel.stats.total_connections.load(Ordering::Relaxed),
+        },
+        "bytes": {
+            "in": tunnel.stats.bytes_in.load(Ordering::Relaxed),
+            "out": tunnel.stats.bytes_out.load(Ordering::Relaxed),
+        },
+        "idle_workers": idle_workers,
+    })
+}
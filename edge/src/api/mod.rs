@@ -1,18 +1,188 @@
-use std::sync::Arc;
+use std::{
+    future::{ready, Ready},
+    sync::Arc,
+};
 
 use actix_web::{
-    error::ErrorForbidden,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::{ErrorForbidden, ErrorServiceUnavailable},
     get,
+    http::{
+        header::{Header, RETRY_AFTER},
+        StatusCode,
+    },
     web::{Data, Json},
-    Responder, Result,
+    FromRequest, HttpRequest, HttpResponse, Responder, Result,
+};
+use actix_web_httpauth::{
+    extractors::AuthenticationError,
+    headers::{
+        authorization::{Authorization, Basic, Bearer},
+        www_authenticate,
+    },
+};
+use serde_json::{json, Value};
+use tokio::{sync::Mutex, time::sleep};
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::Credentials,
+    bans::AuthFailures,
+    config::Scope,
+    state::State,
+    tls::ClientCertificate,
 };
-use actix_web_httpauth::extractors::bearer::BearerAuth;
-use serde_json::json;
-use tokio::sync::Mutex;
 
-use crate::{listener::ListenerMessage, state::State};
+use self::session::ClientSession;
 
+pub mod admin;
 pub mod edge;
+pub mod quota;
+pub mod session;
+
+/// Appends an audit record of an action taken through the API. Requests
+/// whose key was refused are recorded by [`check_auth`] instead.
+pub async fn audit_action<T>(
+    req: &HttpRequest,
+    auth: &Credentials,
+    data: &Data<Arc<Mutex<State>>>,
+    action: &str,
+    params: Value,
+    result: &Result<T>,
+) {
+    let outcome = match result {
+        Ok(_) => "ok".to_string(),
+        Err(e) if is_auth_failure(e.as_response_error().status_code()) => return,
+        Err(e) => error_status(e),
+    };
+
+    let identity = data.lock().await.identify(auth);
+
+    audit::record(AuditEvent {
+        user: identity.as_ref().map(|(user, _)| user.as_str()),
+        key: identity.as_ref().map(|(_, key)| key.as_str()),
+        source: &source_ip(req),
+        action,
+        params,
+        outcome: &outcome,
+    });
+}
+
+/// Refuses requests from banned source IPs before they reach a handler.
+pub fn refuse_banned(req: &ServiceRequest) -> Option<HttpResponse> {
+    let auth_failures = req.app_data::<Data<Arc<AuthFailures>>>()?;
+    let remaining = auth_failures.banned_for(req.peer_addr()?.ip())?;
+
+    Some(
+        HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, remaining.as_secs() + 1))
+            .json(json!({"status": "banned"})),
+    )
+}
+
+/// Records every request that carried an unknown key, or a key without the
+/// scope it needed, and delays answering those with an unknown key. Requests
+/// without credentials are only asking for them, like browsers opening the
+/// dashboard.
+pub async fn check_auth(res: &ServiceResponse) {
+    let req = res.request();
+
+    let (Some(credentials), Some(data), Some(auth_failures), Some(addr)) = (
+        presented_credentials(req),
+        req.app_data::<Data<Arc<Mutex<State>>>>(),
+        req.app_data::<Data<Arc<AuthFailures>>>(),
+        req.peer_addr(),
+    ) else {
+        return;
+    };
+
+    if !is_auth_failure(res.status()) {
+        if auth_failures.has_failures(addr.ip())
+            && data.lock().await.identify(&credentials).is_some()
+        {
+            auth_failures.record_success(addr.ip());
+        }
+        return;
+    }
+
+    // The key may be valid but lack the scope the endpoint needs.
+    let identity = data.lock().await.identify(&credentials);
+
+    audit::record(AuditEvent {
+        user: identity.as_ref().map(|(user, _)| user.as_str()),
+        key: identity.as_ref().map(|(_, key)| key.as_str()),
+        source: &source_ip(req),
+        action: "auth.failed",
+        params: json!({"endpoint": format!("{} {}", req.method(), req.path())}),
+        outcome: res.status().canonical_reason().unwrap_or_default(),
+    });
+
+    if identity.is_none() {
+        sleep(auth_failures.record_failure(addr.ip())).await;
+    }
+}
+
+/// The credentials of the request, or the password of the dashboard's
+/// basic auth.
+fn presented_credentials(req: &HttpRequest) -> Option<Credentials> {
+    if let Ok(basic) = Authorization::<Basic>::parse(req) {
+        return basic
+            .as_ref()
+            .password()
+            .map(|password| Credentials::Key(password.to_string()));
+    }
+
+    request_credentials(req)
+}
+
+/// The bearer token, or else the client certificate of the connection.
+fn request_credentials(req: &HttpRequest) -> Option<Credentials> {
+    if let Ok(bearer) = Authorization::<Bearer>::parse(req) {
+        return Some(Credentials::Key(bearer.as_ref().token().to_string()));
+    }
+
+    req.conn_data::<ClientCertificate>()
+        .map(|certificate| Credentials::Certificate(certificate.0.clone()))
+}
+
+impl FromRequest for Credentials {
+    type Error = AuthenticationError<www_authenticate::bearer::Bearer>;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(request_credentials(req).ok_or_else(|| AuthenticationError::new(Default::default())))
+    }
+}
+
+fn is_auth_failure(status: StatusCode) -> bool {
+    matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+}
+
+pub fn source_ip(req: &HttpRequest) -> String {
+    req.peer_addr()
+        .map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string())
+}
+
+/// The `status` of an error response, e.g. `too many tunnels`.
+fn error_status(e: &actix_web::Error) -> String {
+    let text = e.to_string();
+
+    serde_json::from_str::<Value>(&text)
+        .ok()
+        .and_then(|body| body["status"].as_str().map(str::to_string))
+        .unwrap_or(text)
+}
+
+/// Fails while the edge is shutting down, so clients move elsewhere.
+pub fn check_accepting(state: &State) -> Result<()> {
+    if state.draining {
+        Err(ErrorServiceUnavailable(Json(
+            json!({"status": "shutting down"}),
+        )))
+    } else {
+        Ok(())
+    }
+}
 
 #[get("/api/v1/health")]
 pub async fn health() -> Result<impl Responder> {
@@ -21,12 +191,12 @@ pub async fn health() -> Result<impl Responder> {
 
 #[get("/api/v1/check_authorization")]
 pub async fn check_authorization(
-    auth: BearerAuth,
+    auth: Credentials,
     data: Data<Arc<Mutex<State>>>,
 ) -> Result<impl Responder> {
-    let state = data.lock().await;
+    let mut state = data.lock().await;
 
-    if state.secrets.contains_key(auth.token()) {
+    if state.authenticate(&auth, None).is_some() {
         Ok(Json(json!({"status": "ok"})))
     } else {
         Err(ErrorForbidden(Json(json!({"status": "forbidden"}))))
@@ -34,38 +204,54 @@ pub async fn check_authorization(
 }
 
 #[get("/api/v1/connect")]
-pub async fn connect(auth: BearerAuth, data: Data<Arc<Mutex<State>>>) -> Result<impl Responder> {
-    let state = data.lock().await;
+pub async fn connect(auth: Credentials, data: Data<Arc<Mutex<State>>>) -> Result<impl Responder> {
+    let mut state = data.lock().await;
+
+    check_accepting(&state)?;
 
-    if state.secrets.contains_key(auth.token()) {
-        Ok(Json(json!({"status": "ok", "worker": state.worker_port})))
+    if state.authenticate(&auth, Some(Scope::Workers)).is_some() {
+        Ok(Json(json!({
+            "status": "ok",
+            "worker": state.worker_port,
+            "tls": state.cfg.tls.is_some(),
+        })))
     } else {
         Err(ErrorForbidden(Json(json!({"status": "forbidden"}))))
     }
 }
 
 #[get("/api/v1/goodbye")]
-pub async fn goodbye(auth: BearerAuth, data: Data<Arc<Mutex<State>>>) -> Result<impl Responder> {
+pub async fn goodbye(
+    req: HttpRequest,
+    auth: Credentials,
+    session: ClientSession,
+    data: Data<Arc<Mutex<State>>>,
+) -> Result<impl Responder> {
+    let params = json!({"session": session.0});
+    let result = close_session(&auth, session, &data).await;
+
+    audit_action(&req, &auth, &data, "session.goodbye", params, &result).await;
+    result
+}
+
+async fn close_session(
+    auth: &Credentials,
+    session: ClientSession,
+    data: &Data<Arc<Mutex<State>>>,
+) -> Result<Json<Value>> {
     let mut state = data.lock().await;
 
     let listener_tx = state.listener_tx.clone();
 
     let secret = state
-        .secrets
-        .get_mut(auth.token())
+        .authenticate(auth, Some(Scope::Tunnels))
         .ok_or_else(|| ErrorForbidden(Json(json!({"status": "forbidden"}))))?;
 
-    for tunnel in secret.active_tunnels.drain(..) {
-        if let Err(e) = listener_tx.send(ListenerMessage::Stop { port: tunnel.port }) {
-            eprintln!("failed to send stop message: {}", e);
-        }
-    }
-
-    for worker in secret.workers.drain(..) {
-        if worker.close_tx.send(()).is_err() {
-            eprintln!("failed to send close message: {}", worker.client_addr);
-        }
-    }
+    session.check(secret)?;
+    // Saying goodbye gives up the ports, unlike a lease running out.
+    secret.release_session_tunnels(&session.0, &listener_tx);
+    secret.close_session(&session.0, &listener_tx);
+    state.save_reservations();
 
     Ok(Json(json!({"status": "ok"})))
 }
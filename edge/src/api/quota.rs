@@ -0,0 +1,59 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use actix_web::{
+    error::ErrorForbidden,
+    get,
+    web::{Data, Json},
+    Responder, Result,
+};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::{auth::Credentials, config::Scope, state::State};
+
+/// The user's limits, what is used of them and what remains. Unlimited
+/// ones have a `null` limit and remainder.
+#[get("/api/v1/quota")]
+pub async fn get_quota(auth: Credentials, data: Data<Arc<Mutex<State>>>) -> Result<impl Responder> {
+    let mut state = data.lock().await;
+
+    let secret = state
+        .authenticate(&auth, Some(Scope::Read))
+        .ok_or_else(|| ErrorForbidden(Json(json!({"status": "forbidden"}))))?;
+
+    let usage = secret.usage.clone();
+    let quota = usage.quota.read().unwrap().clone();
+    usage.roll_over(quota.period);
+
+    let idle_workers = secret
+        .sessions
+        .values()
+        .map(|session| session.workers.len())
+        .sum::<usize>();
+
+    Ok(Json(json!({
+        "status": "ok",
+        "period": quota.period,
+        "period_start": usage.period_start.load(Ordering::Relaxed),
+        "bytes": describe(usage.bytes.load(Ordering::Relaxed), quota.bytes),
+        "connections": describe(usage.connections.load(Ordering::Relaxed), quota.connections),
+        "concurrent_connections": describe(
+            usage.active_connections.load(Ordering::Relaxed) as u64,
+            quota.max_connections.map(|max| max as u64),
+        ),
+        "workers": describe(idle_workers as u64, quota.max_workers.map(|max| max as u64)),
+        // Counted like the limit is enforced, offline reservations included.
+        "tunnels": describe(
+            secret.tunnel_count() as u64,
+            Some(secret.max_tunnels as u64),
+        ),
+    })))
+}
+
+fn describe(used: u64, limit: Option<u64>) -> Value {
+    json!({
+        "used": used,
+        "limit": limit,
+        "remaining": limit.map(|limit| limit.saturating_sub(used)),
+    })
+}
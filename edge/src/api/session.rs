@@ -0,0 +1,137 @@
+use std::{
+    future::{ready, Ready},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::Payload,
+    error::{ErrorBadRequest, ErrorForbidden, ErrorGone, ErrorNotFound, ErrorTooManyRequests},
+    post,
+    web::{Data, Json},
+    FromRequest, HttpRequest, Responder, Result,
+};
+use log::info;
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use crate::{
+    api::check_accepting,
+    auth::{generate_key, Credentials},
+    config::Scope,
+    state::{Secret, Session, State},
+};
+
+pub const SESSION_HEADER: &str = "X-Session-Id";
+
+/// Sessions a user can have open at once. Each one is kept until its lease
+/// runs out, so this bounds what a key can make the edge hold.
+const MAX_SESSIONS: usize = 32;
+
+/// Session id sent by the client in the `X-Session-Id` header.
+pub struct ClientSession(pub String);
+
+impl ClientSession {
+    /// Fails unless the session belongs to the given user and is still open.
+    pub fn check(&self, secret: &Secret) -> Result<()> {
+        match secret.sessions.get(&self.0) {
+            Some(session) if session.closed => {
+                Err(ErrorGone(Json(json!({"status": "session closed"}))))
+            }
+            Some(_) => Ok(()),
+            None => Err(ErrorNotFound(Json(json!({"status": "unknown session"})))),
+        }
+    }
+}
+
+impl FromRequest for ClientSession {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.headers()
+                .get(SESSION_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| ClientSession(value.to_string()))
+                .ok_or_else(|| ErrorBadRequest(Json(json!({"status": "missing session"})))),
+        )
+    }
+}
+
+#[post("/api/v1/session")]
+pub async fn open_session(
+    req: HttpRequest,
+    auth: Credentials,
+    data: Data<Arc<Mutex<State>>>,
+) -> Result<impl Responder> {
+    let mut state = data.lock().await;
+
+    check_accepting(&state)?;
+
+    let lease = state.cfg.session_lease;
+
+    let secret = state
+        .authenticate(&auth, Some(Scope::Tunnels))
+        .ok_or_else(|| ErrorForbidden(Json(json!({"status": "forbidden"}))))?;
+
+    let open = secret
+        .sessions
+        .values()
+        .filter(|session| !session.closed)
+        .count();
+
+    if open >= MAX_SESSIONS {
+        return Err(ErrorTooManyRequests(Json(
+            json!({"status": "too many sessions"}),
+        )));
+    }
+
+    let id = generate_key();
+    let client_addr = req
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+
+    info!(
+        "user {} opened session {id} from {client_addr}",
+        secret.name
+    );
+
+    secret.sessions.insert(
+        id.clone(),
+        Session {
+            client_addr,
+            lease_expires: Instant::now() + Duration::from_secs(lease),
+            workers: vec![],
+            closed: false,
+        },
+    );
+
+    Ok(Json(json!({"status": "ok", "session": id, "lease": lease})))
+}
+
+#[post("/api/v1/session/renew")]
+pub async fn renew_session(
+    auth: Credentials,
+    session: ClientSession,
+    data: Data<Arc<Mutex<State>>>,
+) -> Result<impl Responder> {
+    let mut state = data.lock().await;
+
+    check_accepting(&state)?;
+
+    let lease = state.cfg.session_lease;
+
+    let secret = state
+        .authenticate(&auth, None)
+        .ok_or_else(|| ErrorForbidden(Json(json!({"status": "forbidden"}))))?;
+
+    session.check(secret)?;
+
+    if let Some(s) = secret.sessions.get_mut(&session.0) {
+        s.lease_expires = Instant::now() + Duration::from_secs(lease);
+    }
+
+    Ok(Json(json!({"status": "ok", "lease": lease})))
+}
@@ -0,0 +1,478 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Result};
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use rand::random;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedSender},
+    oneshot,
+};
+
+use crate::{
+    auth::{now, to_hex},
+    config::{with_suffix, Configuration},
+};
+
+/// Set once the edge or a CLI command that changes users opened the audit
+/// log. Records are dropped without it, e.g. while checking the config.
+static AUDIT_LOG: OnceLock<AuditLog> = OnceLock::new();
+
+/// Set once the edge serves. Records are then written on a thread of their
+/// own, so actions don't wait for the disk, and in the order they were taken.
+static AUDIT_WRITER: OnceLock<UnboundedSender<WriterJob>> = OnceLock::new();
+
+enum WriterJob {
+    Record(AuditRecord),
+    /// Answered once every record sent before is written.
+    Flush(oneshot::Sender<()>),
+}
+
+/// `prev` of the first record.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Source of actions taken through the CLI.
+pub const LOCAL_SOURCE: &str = "local";
+
+/// A control-plane action, as passed to [`record`].
+pub struct AuditEvent<'a> {
+    /// User and key name the action was taken with, unknown for failed
+    /// authentication and CLI commands.
+    pub user: Option<&'a str>,
+    pub key: Option<&'a str>,
+    /// IP address of the caller, or [`LOCAL_SOURCE`].
+    pub source: &'a str,
+    pub action: &'a str,
+    pub params: Value,
+    /// `ok`, or why the action failed.
+    pub outcome: &'a str,
+}
+
+/// One line of the audit log. Every record holds the hash of the one before
+/// it, so editing or removing a record breaks the chain from there on. The
+/// hashes are keyed, so a modified chain can't be made consistent again
+/// without the key.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub user: Option<String>,
+    pub key: Option<String>,
+    pub source: String,
+    pub action: String,
+    pub params: Value,
+    pub outcome: String,
+    pub prev: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ChainedRecord {
+    #[serde(flatten)]
+    record: AuditRecord,
+    /// HMAC-SHA256 of the record without this field, keyed with the audit
+    /// key.
+    hash: String,
+}
+
+/// The audit log, `<path>` being the file written to and `<path>.<n>` the
+/// full ones, oldest first.
+struct AuditLog {
+    path: PathBuf,
+    key: Vec<u8>,
+    max_size: u64,
+    writer: Mutex<Writer>,
+}
+
+struct Writer {
+    file: File,
+    /// Size of the file after our last write. Another process appending to
+    /// it, like the edge taking over during an upgrade, changes it.
+    size: u64,
+    next_seq: u64,
+    last_hash: String,
+}
+
+impl AuditLog {
+    /// Continues the chain from the last record written by another process
+    /// since we last wrote, reading only what it added.
+    fn sync(&self, writer: &mut Writer) -> Result<()> {
+        let ours = writer.file.metadata()?.len();
+        let current = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        if ours != current {
+            // The other process moved on to a new file, and ours is full.
+            writer.file = open_append(&self.path)?;
+            writer.size = current;
+            (writer.next_seq, writer.last_hash) = chain_end(&self.path, &self.key)?;
+        } else if ours != writer.size {
+            let mut file = File::open(&self.path)?;
+            file.seek(SeekFrom::Start(writer.size))?;
+
+            let mut added = String::new();
+            file.read_to_string(&mut added)?;
+
+            if let Some(last) = parse_records(&added).last() {
+                (writer.next_seq, writer.last_hash) = (last.record.seq + 1, last.hash.clone());
+            }
+
+            writer.size = ours;
+        }
+
+        Ok(())
+    }
+
+    /// Chains the record to the last one written and appends it.
+    fn append(&self, mut record: AuditRecord) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        self.sync(&mut writer)?;
+
+        record.seq = writer.next_seq;
+        record.prev = writer.last_hash.clone();
+
+        let hash = hash_record(&record, &self.key)?;
+        let mut line = serde_json::to_vec(&ChainedRecord {
+            record,
+            hash: hash.clone(),
+        })?;
+        line.push(b'\n');
+
+        if writer.size > 0 && writer.size + line.len() as u64 > self.max_size {
+            let full = with_suffix(&self.path, &(segments(&self.path).len() + 1).to_string());
+            fs::rename(&self.path, full)?;
+
+            writer.file = open_append(&self.path)?;
+            writer.size = 0;
+        }
+
+        writer.file.write_all(&line)?;
+        writer.size += line.len() as u64;
+        writer.next_seq += 1;
+        writer.last_hash = hash;
+
+        Ok(())
+    }
+}
+
+/// Opens the audit log, continuing the chain of the records already in it.
+pub fn init_audit_log(cfg: &Configuration) -> Result<()> {
+    let path = cfg.audit_log_path();
+    let key = load_key(&cfg.audit_key_path(), true)?;
+
+    // Only the first file starts the chain, and full ones are left to
+    // `verify`.
+    let start = segments(&path).is_empty().then(|| (0, GENESIS.to_string()));
+    let (records, broken) = read_chain(&path, &key, start)?;
+
+    if let Some(problem) = broken {
+        warn!("audit log {} is not intact: {problem}", path.display());
+    }
+
+    let (next_seq, last_hash) = match records.last() {
+        Some(last) => (last.record.seq + 1, last.hash.clone()),
+        None => chain_end(&path, &key)?,
+    };
+
+    let file = open_append(&path)
+        .map_err(|e| anyhow!("unable to open audit log {}: {e}", path.display()))?;
+    let size = file.metadata()?.len();
+
+    let _ = AUDIT_LOG.set(AuditLog {
+        path,
+        key,
+        max_size: cfg.audit_max_size,
+        writer: Mutex::new(Writer {
+            file,
+            size,
+            next_seq,
+            last_hash,
+        }),
+    });
+
+    Ok(())
+}
+
+/// Writes the records passed to [`record`] from now on, on a thread of its
+/// own.
+pub fn spawn_audit_writer() {
+    let Some(log) = AUDIT_LOG.get() else {
+        return;
+    };
+
+    let (tx, mut rx) = unbounded_channel();
+
+    thread::spawn(move || {
+        while let Some(job) = rx.blocking_recv() {
+            match job {
+                WriterJob::Record(record) => {
+                    if let Err(e) = log.append(record) {
+                        error!("failed to write audit log: {e}");
+                    }
+                }
+                WriterJob::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    });
+
+    let _ = AUDIT_WRITER.set(tx);
+}
+
+/// Waits for the records passed to [`record`] so far to be written.
+pub async fn flush_audit_log() {
+    let Some(writer) = AUDIT_WRITER.get() else {
+        return;
+    };
+
+    let (tx, rx) = oneshot::channel();
+
+    if writer.send(WriterJob::Flush(tx)).is_ok() {
+        let _ = rx.await;
+    }
+}
+
+pub fn record(event: AuditEvent) {
+    let Some(log) = AUDIT_LOG.get() else {
+        return;
+    };
+
+    // Chained once it is written.
+    let record = AuditRecord {
+        seq: 0,
+        timestamp: now(),
+        user: event.user.map(str::to_string),
+        key: event.key.map(str::to_string),
+        source: event.source.to_string(),
+        action: event.action.to_string(),
+        params: event.params,
+        outcome: event.outcome.to_string(),
+        prev: String::new(),
+    };
+
+    let result = match AUDIT_WRITER.get() {
+        Some(writer) => writer
+            .send(WriterJob::Record(record))
+            .map_err(|_| anyhow!("the audit writer is gone")),
+        None => log.append(record),
+    };
+
+    if let Err(e) = result {
+        error!("failed to write audit log: {e}");
+    }
+}
+
+/// Records matching every given filter, the latest `limit` of them, along
+/// with the problem found in the chain if it is not intact. Full files are
+/// only read when the current one doesn't hold enough records.
+pub fn query(
+    user: Option<&str>,
+    action: Option<&str>,
+    since: u64,
+    limit: usize,
+) -> Result<(Vec<AuditRecord>, Option<String>)> {
+    let Some(log) = AUDIT_LOG.get() else {
+        bail!("the audit log is not enabled");
+    };
+
+    let mut files = segments(&log.path);
+    files.push(log.path.clone());
+
+    let mut matching = vec![];
+    let mut broken = None;
+
+    for file in files.iter().rev() {
+        let (records, problem) = read_chain(file, &log.key, None)?;
+
+        if let Some(problem) = problem {
+            broken.get_or_insert(format!("{}: {problem}", file.display()));
+        }
+
+        let older = records
+            .first()
+            .is_some_and(|first| first.record.timestamp < since);
+
+        let mut found = records
+            .into_iter()
+            .map(|chained| chained.record)
+            .filter(|record| {
+                record.timestamp >= since
+                    && user.is_none_or(|user| record.user.as_deref() == Some(user))
+                    && action.is_none_or(|action| record.action == action)
+            })
+            .collect::<Vec<_>>();
+
+        found.append(&mut matching);
+        matching = found;
+
+        if older || matching.len() >= limit {
+            break;
+        }
+    }
+
+    matching.drain(..matching.len().saturating_sub(limit));
+
+    Ok((matching, broken))
+}
+
+/// Checks every record of the audit log, full files included, returning
+/// how many there are and the hash of the last one, which can be kept
+/// elsewhere to also detect records being removed from the end.
+pub fn verify(cfg: &Configuration) -> Result<(u64, String)> {
+    let path = cfg.audit_log_path();
+    let key = load_key(&cfg.audit_key_path(), false)?;
+
+    let mut files = segments(&path);
+    files.push(path);
+
+    let mut end = (0, GENESIS.to_string());
+
+    for file in files {
+        let (records, broken) = read_chain(&file, &key, Some(end.clone()))?;
+
+        if let Some(problem) = broken {
+            bail!("{}: {problem}", file.display());
+        }
+
+        if let Some(last) = records.last() {
+            end = (last.record.seq + 1, last.hash.clone());
+        }
+    }
+
+    Ok(end)
+}
+
+/// Reads the key the chain is kept with, creating a random one if asked to.
+fn load_key(path: &Path, create: bool) -> Result<Vec<u8>> {
+    match fs::read_to_string(path) {
+        Ok(key) if key.trim().is_empty() => bail!("audit key {} is empty", path.display()),
+        Ok(key) => Ok(key.trim().as_bytes().to_vec()),
+        Err(e) if e.kind() == ErrorKind::NotFound && create => {
+            let key = to_hex(&random::<[u8; 32]>());
+
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+            options
+                .open(path)
+                .and_then(|mut file| file.write_all(key.as_bytes()))
+                .map_err(|e| anyhow!("unable to create audit key {}: {e}", path.display()))?;
+
+            Ok(key.into_bytes())
+        }
+        Err(e) => Err(anyhow!("unable to read audit key {}: {e}", path.display())),
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// The full files of the audit log, oldest first.
+fn segments(path: &Path) -> Vec<PathBuf> {
+    (1..)
+        .map(|n: usize| with_suffix(path, &n.to_string()))
+        .take_while(|segment| segment.exists())
+        .collect()
+}
+
+/// Sequence number and `prev` of the record following the last one written,
+/// which is in the latest full file if the current one is still empty.
+fn chain_end(path: &Path, key: &[u8]) -> Result<(u64, String)> {
+    let mut files = segments(path);
+    files.push(path.to_path_buf());
+
+    for file in files.iter().rev() {
+        let (records, _) = read_chain(file, key, None)?;
+
+        if let Some(last) = records.last() {
+            return Ok((last.record.seq + 1, last.hash.clone()));
+        }
+    }
+
+    Ok((0, GENESIS.to_string()))
+}
+
+/// Records in the given text, skipping a last line that is still being
+/// written.
+fn parse_records(text: &str) -> Vec<ChainedRecord> {
+    complete_lines(text)
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+fn complete_lines(text: &str) -> impl Iterator<Item = &str> {
+    let complete = text.rfind('\n').map_or("", |end| &text[..end]);
+    complete.lines()
+}
+
+/// Reads every record of one file that parses, and describes the first place
+/// where the chain is broken, if any. The file has to start where `start`
+/// says, or anywhere without it.
+fn read_chain(
+    path: &Path,
+    key: &[u8],
+    start: Option<(u64, String)>,
+) -> Result<(Vec<ChainedRecord>, Option<String>)> {
+    let file = match fs::read_to_string(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((vec![], None)),
+        Err(e) => return Err(anyhow!("unable to read audit log {}: {e}", path.display())),
+    };
+
+    let mut records: Vec<ChainedRecord> = vec![];
+    let mut broken = None;
+    let mut expected = start;
+
+    for (index, line) in complete_lines(&file).enumerate() {
+        let chained: ChainedRecord = match serde_json::from_str(line) {
+            Ok(chained) => chained,
+            Err(e) => {
+                broken.get_or_insert(format!("line {} is not a record: {e}", index + 1));
+                continue;
+            }
+        };
+
+        let seq = chained.record.seq;
+        let (expected_seq, expected_prev) = expected
+            .take()
+            .unwrap_or_else(|| (seq, chained.record.prev.clone()));
+
+        if broken.is_none() {
+            if seq != expected_seq {
+                broken = Some(format!(
+                    "record {seq} found where {expected_seq} was expected"
+                ));
+            } else if chained.record.prev != expected_prev {
+                broken = Some(format!("record {seq} does not follow the previous one"));
+            } else if hash_record(&chained.record, key)? != chained.hash {
+                broken = Some(format!("record {seq} was modified"));
+            }
+        }
+
+        expected = Some((seq + 1, chained.hash.clone()));
+        records.push(chained);
+    }
+
+    Ok((records, broken))
+}
+
+fn hash_record(record: &AuditRecord, key: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+    mac.update(&serde_json::to_vec(record)?);
+    Ok(to_hex(&mac.finalize().into_bytes()))
+}
@@ -0,0 +1,89 @@
+use std::{
+    hint::black_box,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rand::random;
+use sha2::{Digest, Sha256};
+
+const HASH_SCHEME: &str = "sha256";
+
+/// Key name recorded for callers that authenticated with a certificate.
+pub const CERTIFICATE_KEY_NAME: &str = "certificate";
+
+/// What a caller authenticates with.
+pub enum Credentials {
+    /// A secret key, sent as a bearer token or on a worker's first line.
+    Key(String),
+    /// The names of a verified client certificate, one of which names a user.
+    Certificate(Vec<String>),
+}
+
+pub fn generate_key() -> String {
+    let bytes = random::<[u8; 32]>();
+    to_hex(&bytes)
+}
+
+/// Hashes a secret key with a random salt.
+/// The result has the form `sha256$<salt>$<digest>`, both parts hex encoded.
+pub fn hash_key(key: &str) -> String {
+    let salt = to_hex(&random::<[u8; 16]>());
+    let digest = digest(&salt, key);
+
+    format!("{HASH_SCHEME}${salt}${digest}")
+}
+
+/// Checks a secret key against a hash produced by [`hash_key`].
+/// The digests are compared in constant time.
+pub fn verify_key(key: &str, hash: &str) -> bool {
+    let mut parts = hash.splitn(3, '$');
+
+    let (Some(HASH_SCHEME), Some(salt), Some(expected)) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+
+    constant_time_eq(digest(salt, key).as_bytes(), expected.as_bytes())
+}
+
+/// Shortens a hash to something recognizable that is safe to print.
+pub fn mask_hash(hash: &str) -> String {
+    let digest = hash.rsplit('$').next().unwrap_or_default();
+    let tail = digest
+        .get(digest.len().saturating_sub(6)..)
+        .unwrap_or_default();
+
+    format!("****{tail}")
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    // Kept opaque so the compiler can't stop early once a byte differs.
+    a.iter()
+        .zip(b)
+        .fold(0, |acc, (x, y)| black_box(acc | (x ^ y)))
+        == 0
+}
+
+/// Current time as a unix timestamp in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn digest(salt: &str, key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(key.as_bytes());
+    to_hex(&hasher.finalize())
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,244 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    audit::{self, AuditEvent},
+    auth::now,
+    config::AuthBanConfig,
+};
+
+/// Delay before answering the first failed authentication of a source IP.
+/// It doubles with every further failure.
+const BASE_DELAY: Duration = Duration::from_millis(250);
+
+const MAX_DELAY: Duration = Duration::from_secs(8);
+
+/// Sources tracked at most. Past it, expired entries are forgotten, and if
+/// that isn't enough, the [`EVICTED_ENTRIES`] sources with the oldest
+/// failures that aren't banned, so the next ones find room without a scan.
+const MAX_ENTRIES: usize = 100_000;
+
+const EVICTED_ENTRIES: usize = MAX_ENTRIES / 10;
+
+/// How often expired failures and bans are forgotten.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Failed authentications per source IP, on the API and the worker port.
+/// Every failure is answered a bit later than the previous one, and a source
+/// IP failing too often within the window is banned for a while. IPv6
+/// addresses are counted per /64, which is usually what a single host gets.
+pub struct AuthFailures {
+    config: AuthBanConfig,
+    entries: Mutex<HashMap<IpAddr, FailureEntry>>,
+}
+
+struct FailureEntry {
+    failures: u32,
+    first_failure: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// A source IP with recent failures, as listed by the admin API.
+#[derive(Serialize)]
+pub struct BanEntry {
+    /// The IPv4 address, or the network of the IPv6 /64.
+    pub ip: IpAddr,
+    pub failures: u32,
+    /// Unix timestamp at which the ban ends, if the IP is banned.
+    pub banned_until: Option<u64>,
+}
+
+impl FailureEntry {
+    fn is_stale(&self, config: &AuthBanConfig, now: Instant) -> bool {
+        match self.banned_until {
+            Some(until) => until <= now,
+            None => self.first_failure + Duration::from_secs(config.window) <= now,
+        }
+    }
+}
+
+/// The address failures of the given IP are counted under.
+fn source(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u128::MAX >> 64))),
+        },
+    }
+}
+
+impl AuthFailures {
+    pub fn new(config: AuthBanConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Time left on the IP's ban, if it is banned.
+    pub fn banned_for(&self, ip: IpAddr) -> Option<Duration> {
+        let entries = self.entries.lock().unwrap();
+        let until = entries.get(&source(ip))?.banned_until?;
+
+        until.checked_duration_since(Instant::now())
+    }
+
+    /// Counts a failed authentication, banning the IP once it reached the
+    /// limit. Returns how long to wait before answering.
+    pub fn record_failure(&self, ip: IpAddr) -> Duration {
+        let now = Instant::now();
+        let ip = source(ip);
+        let mut entries = self.entries.lock().unwrap();
+        let mut banned = None;
+
+        // Everyone tracked is banned, so this one can wait too.
+        if entries.len() >= MAX_ENTRIES
+            && !entries.contains_key(&ip)
+            && !self.make_room(&mut entries, now)
+        {
+            return MAX_DELAY;
+        }
+
+        let entry = entries.entry(ip).or_insert(FailureEntry {
+            failures: 0,
+            first_failure: now,
+            banned_until: None,
+        });
+
+        // Sweeps only run every so often, so an expired entry may still be
+        // around. It starts over.
+        if entry.is_stale(&self.config, now) {
+            *entry = FailureEntry {
+                failures: 0,
+                first_failure: now,
+                banned_until: None,
+            };
+        }
+
+        entry.failures += 1;
+
+        if entry.banned_until.is_none() && entry.failures >= self.config.max_failures {
+            entry.banned_until = Some(now + Duration::from_secs(self.config.duration));
+
+            warn!(
+                "banning {ip} for {}s after {} failed authentications",
+                self.config.duration, entry.failures
+            );
+
+            banned = Some(entry.failures);
+        }
+
+        let delay = BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(entry.failures - 1))
+            .min(MAX_DELAY);
+        drop(entries);
+
+        if let Some(failures) = banned {
+            audit::record(AuditEvent {
+                user: None,
+                key: None,
+                source: &ip.to_string(),
+                action: "auth.banned",
+                params: json!({"failures": failures}),
+                outcome: &format!("banned for {}s", self.config.duration),
+            });
+        }
+
+        delay
+    }
+
+    /// Forgets the failures of an IP that authenticated, unless it is banned.
+    pub fn record_success(&self, ip: IpAddr) {
+        let ip = source(ip);
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries
+            .get(&ip)
+            .is_some_and(|entry| entry.banned_until.is_none())
+        {
+            entries.remove(&ip);
+        }
+    }
+
+    /// Whether the IP has failures that [`AuthFailures::record_success`]
+    /// would clear, which is cheaper to check than authenticating.
+    pub fn has_failures(&self, ip: IpAddr) -> bool {
+        self.entries.lock().unwrap().contains_key(&source(ip))
+    }
+
+    /// Lifts the IP's ban and forgets its failures.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.entries.lock().unwrap().remove(&source(ip)).is_some()
+    }
+
+    /// Frees entries for new sources, returning false if every source
+    /// tracked is banned.
+    fn make_room(&self, entries: &mut HashMap<IpAddr, FailureEntry>, now: Instant) -> bool {
+        entries.retain(|_, entry| !entry.is_stale(&self.config, now));
+
+        if entries.len() < MAX_ENTRIES {
+            return true;
+        }
+
+        let mut unbanned = entries
+            .iter()
+            .filter(|(_, entry)| entry.banned_until.is_none())
+            .map(|(ip, entry)| (entry.first_failure, *ip))
+            .collect::<Vec<_>>();
+
+        if unbanned.is_empty() {
+            return false;
+        }
+
+        let evicted = EVICTED_ENTRIES.min(unbanned.len());
+        if evicted < unbanned.len() {
+            unbanned.select_nth_unstable(evicted);
+        }
+
+        for (_, ip) in &unbanned[..evicted] {
+            entries.remove(ip);
+        }
+
+        true
+    }
+
+    /// Forgets failures that left the window and bans that are over.
+    pub fn sweep(&self) {
+        let now = Instant::now();
+
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, entry| !entry.is_stale(&self.config, now));
+    }
+
+    /// Source IPs with recent failures, banned ones first.
+    pub fn list(&self) -> Vec<BanEntry> {
+        let current = Instant::now();
+        let entries = self.entries.lock().unwrap();
+
+        let mut list = entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_stale(&self.config, current))
+            .map(|(ip, entry)| BanEntry {
+                ip: *ip,
+                failures: entry.failures,
+                banned_until: entry
+                    .banned_until
+                    .map(|until| now() + until.duration_since(current).as_secs()),
+            })
+            .collect::<Vec<_>>();
+
+        list.sort_by_key(|entry| (entry.banned_until.is_none(), entry.ip));
+        list
+    }
+}
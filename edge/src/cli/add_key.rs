@@ -0,0 +1,46 @@
+use anyhow::{anyhow, bail, Result};
+use log::info;
+
+use crate::{
+    auth::{generate_key, hash_key, now},
+    config::{Key, Scope},
+    storage::Storage,
+};
+
+pub fn add_key(
+    storage: &mut dyn Storage,
+    user: String,
+    key_name: String,
+    scopes: Vec<Scope>,
+    expires_in: Option<u64>,
+) -> Result<String> {
+    let name = user.to_lowercase();
+    let mut secret = storage
+        .user(&name)?
+        .ok_or_else(|| anyhow!("user not found"))?;
+
+    if secret.keys.contains_key(&key_name) {
+        bail!("key already exists");
+    }
+
+    let scopes = if scopes.is_empty() {
+        Scope::defaults()
+    } else {
+        scopes
+    };
+
+    let key = generate_key();
+    let expires_at = expires_in.map(|secs| now() + secs);
+
+    secret.keys.insert(
+        key_name.clone(),
+        Key::new(hash_key(&key), scopes, expires_at),
+    );
+
+    storage.save_user(&name, &secret)?;
+
+    info!("key {key_name} added to user {user}");
+
+    // Only the hash is stored, so this is the one chance to see the key.
+    Ok(key)
+}
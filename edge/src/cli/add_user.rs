@@ -1,27 +1,46 @@
+use std::collections::HashMap;
+
 use anyhow::{bail, Result};
 use log::info;
-use rand::random;
 
-use crate::config::{write_config, Configuration, Secret};
+use crate::{
+    auth::{generate_key, hash_key},
+    config::{Key, Quota, Scope, Secret, DEFAULT_KEY_NAME},
+    storage::Storage,
+};
 
-pub fn add_user(cfg: &mut Configuration, name: String, max_tunnels: Option<usize>) -> Result<()> {
-    if cfg.secrets.contains_key(&name.to_lowercase()) {
+pub fn add_user(
+    storage: &mut dyn Storage,
+    name: String,
+    max_tunnels: Option<usize>,
+) -> Result<String> {
+    if storage.user(&name.to_lowercase())?.is_some() {
         bail!("user already exists");
     }
 
     let max_tunnels = max_tunnels.unwrap_or(5);
     let key = generate_key();
 
-    cfg.secrets
-        .insert(name.to_lowercase(), Secret { max_tunnels, key });
+    let mut keys = HashMap::new();
+    keys.insert(
+        DEFAULT_KEY_NAME.to_string(),
+        Key::new(hash_key(&key), Scope::defaults(), None),
+    );
 
-    write_config(cfg)?;
+    storage.save_user(
+        &name.to_lowercase(),
+        &Secret {
+            max_tunnels,
+            key: None,
+            key_hash: None,
+            keys,
+            quota: Quota::default(),
+            certificate_scopes: None,
+        },
+    )?;
 
     info!("user {} added", name);
-    Ok(())
-}
 
-fn generate_key() -> String {
-    let bytes = random::<[u8; 32]>();
-    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    // Only the hash is stored, so this is the one chance to see the key.
+    Ok(key)
 }
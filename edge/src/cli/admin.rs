@@ -0,0 +1,168 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use log::{error, info};
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::Mutex,
+    time::timeout,
+};
+
+use crate::{
+    cli::{
+        audit_command, kick_client::kick_client, run_storage_command, stats::stats,
+        tunnels::tunnels, Commands,
+    },
+    config::with_suffix,
+    state::State,
+};
+
+/// How long a connection may take to send its command or read the answer.
+/// Commands are handled one at a time, so a stalled one would hold up every
+/// other.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The admin socket lives next to the configuration it manages.
+pub fn socket_path(config_path: &Path) -> PathBuf {
+    with_suffix(config_path, "sock")
+}
+
+/// Sends a command to the edge serving the socket. Returns `None` when no
+/// edge is running, so the caller can fall back to editing the file.
+pub async fn send_command(path: &Path, command: &Commands) -> Result<Option<String>> {
+    let stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            return Ok(None)
+        }
+        Err(e) => bail!("unable to connect to admin socket {}: {e}", path.display()),
+    };
+
+    let (read, mut write) = stream.into_split();
+
+    let mut request = serde_json::to_vec(command)?;
+    request.push(b'\n');
+    write.write_all(&request).await?;
+
+    let mut response = String::new();
+    BufReader::new(read).read_line(&mut response).await?;
+
+    let response: Result<String, String> = serde_json::from_str(&response)
+        .map_err(|e| anyhow!("invalid response from admin socket: {e}"))?;
+
+    response.map(Some).map_err(|e| anyhow!(e))
+}
+
+/// Binds the admin socket, refusing to take over one that is still in use
+/// unless the edge serving it is being upgraded. Returns the socket's inode,
+/// for [`remove_admin_socket`].
+pub async fn bind_admin_socket(path: &Path, takeover: bool) -> Result<(UnixListener, u64)> {
+    if path.exists() {
+        if !takeover && UnixStream::connect(path).await.is_ok() {
+            bail!("another edge is already serving {}", path.display());
+        }
+
+        // Left behind by an edge that did not shut down cleanly, or still
+        // used by the edge handing over to this one.
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+    Ok((listener, fs::metadata(path)?.ino()))
+}
+
+/// Removes the admin socket on exit, unless another edge replaced it since.
+pub fn remove_admin_socket(path: &Path, inode: u64) {
+    if fs::metadata(path).is_ok_and(|metadata| metadata.ino() == inode) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Answers commands sent by the CLI. Changes are written to the storage and
+/// then applied to the live state.
+pub async fn serve_admin(listener: UnixListener, state: Arc<Mutex<State>>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("failed to accept admin connection: {e}");
+                continue;
+            }
+        };
+
+        // Commands are handled one at a time, so concurrent edits never race
+        // on the storage.
+        if let Err(e) = handle_admin_connection(stream, &state).await {
+            error!("failed to handle admin connection: {e}");
+        }
+    }
+}
+
+async fn handle_admin_connection(stream: UnixStream, state: &Arc<Mutex<State>>) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+
+    let mut request = String::new();
+    timeout(
+        CONNECTION_TIMEOUT,
+        BufReader::new(read).read_line(&mut request),
+    )
+    .await
+    .map_err(|_| anyhow!("no command received in time"))??;
+
+    let command: Commands = serde_json::from_str(&request)?;
+    info!("admin command: {command:?}");
+
+    let response = execute(command, state).await.map_err(|e| e.to_string());
+
+    let mut response = serde_json::to_vec(&response)?;
+    response.push(b'\n');
+    timeout(CONNECTION_TIMEOUT, write.write_all(&response))
+        .await
+        .map_err(|_| anyhow!("the answer was not read in time"))??;
+
+    Ok(())
+}
+
+async fn execute(command: Commands, state: &Arc<Mutex<State>>) -> Result<String> {
+    match command {
+        Commands::Tunnels {} => tunnels(&*state.lock().await),
+        Commands::KickClient { user, session } => {
+            let action = (
+                "session.kick",
+                json!({"user": user.to_lowercase(), "session": session}),
+            );
+            let result = kick_client(&mut *state.lock().await, user, session);
+
+            audit_command(Some(action), &result);
+            result
+        }
+        Commands::Stats {} => stats(&*state.lock().await),
+        command => {
+            // So usage includes the connections that already finished.
+            State::flush_traffic(state).await;
+
+            let storage = state.lock().await.storage.clone();
+            let (output, users) = storage
+                .run(move |storage| -> Result<_> {
+                    let output = run_storage_command(storage, command)?;
+                    Ok((output, storage.users()?))
+                })
+                .await??;
+
+            state.lock().await.sync_secrets(&users);
+
+            Ok(output)
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::{collections::HashMap, fs::read_to_string, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use toml::{from_str, Table, Value};
+
+use crate::{
+    auth::verify_key,
+    config::{AuthBanConfig, Key, Quota, Scope, Secret, TlsConfig},
+    storage::{sqlite::SqliteStorage, sqlite_path, Storage},
+    tls,
+};
+
+/// Validates the configuration file without migrating or rewriting it, and
+/// reports every problem found, with the key it was found at.
+pub fn check_config(path: &Path) -> Result<()> {
+    let file = read_to_string(path)
+        .map_err(|e| anyhow!("unable to read config {}: {e}", path.display()))?;
+    let table: Table = from_str(&file).map_err(|e| anyhow!("{}: {e}", path.display()))?;
+
+    let mut problems = vec![];
+
+    match table.get("port") {
+        None => problems.push(("port".to_string(), "missing".to_string())),
+        Some(Value::Integer(port)) if (1..=u16::MAX as i64).contains(port) => {}
+        Some(other) => problems.push(("port".to_string(), format!("invalid port {other}"))),
+    }
+
+    match table.get("session_lease") {
+        None => {}
+        Some(Value::Integer(lease)) if *lease > 0 => {}
+        Some(other) => problems.push((
+            "session_lease".to_string(),
+            format!("expected a positive number of seconds, found {other}"),
+        )),
+    }
+
+    match table.get("audit_log") {
+        None | Some(Value::String(_)) => {}
+        Some(other) => problems.push((
+            "audit_log".to_string(),
+            format!("expected a path, found {}", other.type_str()),
+        )),
+    }
+
+    match table.get("audit_max_size") {
+        None => {}
+        Some(Value::Integer(size)) if *size > 0 => {}
+        Some(other) => problems.push((
+            "audit_max_size".to_string(),
+            format!("expected a positive number of bytes, found {other}"),
+        )),
+    }
+
+    match table.get("audit_key") {
+        None | Some(Value::String(_)) => {}
+        Some(other) => problems.push((
+            "audit_key".to_string(),
+            format!("expected a path, found {}", other.type_str()),
+        )),
+    }
+
+    if let Some(auth_ban) = table.get("auth_ban") {
+        if let Err(e) = auth_ban.clone().try_into::<AuthBanConfig>() {
+            problems.push(("auth_ban".to_string(), e.to_string()));
+        }
+    }
+
+    if let Some(tls_config) = table.get("tls") {
+        match tls_config.clone().try_into::<TlsConfig>() {
+            Ok(tls_config) => {
+                if let Err(e) = tls::acceptor(&tls_config) {
+                    problems.push(("tls".to_string(), e.to_string()));
+                }
+            }
+            Err(e) => problems.push(("tls".to_string(), e.to_string())),
+        }
+    }
+
+    let mut database = None;
+
+    match table.get("storage") {
+        None => {}
+        Some(Value::Table(storage)) => match storage.get("backend") {
+            None => problems.push(("storage.backend".to_string(), "missing".to_string())),
+            Some(Value::String(backend)) if backend == "toml" => {}
+            Some(Value::String(backend)) if backend == "sqlite" => match storage.get("path") {
+                None => problems.push(("storage.path".to_string(), "missing".to_string())),
+                Some(Value::String(db)) => database = Some(sqlite_path(path, Path::new(db))),
+                Some(other) => problems.push((
+                    "storage.path".to_string(),
+                    format!("expected a string, found {}", other.type_str()),
+                )),
+            },
+            Some(other) => problems.push((
+                "storage.backend".to_string(),
+                format!("unknown backend {other}, expected \"toml\" or \"sqlite\""),
+            )),
+        },
+        Some(other) => problems.push((
+            "storage".to_string(),
+            format!("expected a table, found {}", other.type_str()),
+        )),
+    }
+
+    // A database that doesn't exist yet starts with the users of the file.
+    let mut unchecked = None;
+    let stored = database
+        .filter(|db| db.exists())
+        .and_then(|db| match stored_users(&db) {
+            Ok(Some(users)) => Some(users),
+            Ok(None) => {
+                unchecked = Some(format!("{} has an older schema", db.display()));
+                None
+            }
+            Err(e) => {
+                problems.push(("storage.path".to_string(), e.to_string()));
+                None
+            }
+        });
+
+    // The users of the file are ignored once they are in the database.
+    let in_database = stored.is_some() || unchecked.is_some();
+
+    let secrets = match table.get("secrets") {
+        _ if in_database => Table::new(),
+        None => Table::new(),
+        Some(Value::Table(secrets)) => secrets.clone(),
+        Some(other) => {
+            problems.push((
+                "secrets".to_string(),
+                format!("expected a table, found {}", other.type_str()),
+            ));
+            Table::new()
+        }
+    };
+
+    // (location, plaintext key) and (location, hash) of every key, to find
+    // secrets shared between users.
+    let mut plaintext = vec![];
+    let mut hashes = vec![];
+
+    for (user, secret) in &secrets {
+        let location = format!("secrets.{user}");
+
+        let Value::Table(secret) = secret else {
+            problems.push((
+                location,
+                format!("expected a table, found {}", secret.type_str()),
+            ));
+            continue;
+        };
+
+        match secret.get("max_tunnels") {
+            None => problems.push((format!("{location}.max_tunnels"), "missing".to_string())),
+            Some(Value::Integer(max)) if *max >= 0 => {}
+            Some(other) => problems.push((
+                format!("{location}.max_tunnels"),
+                format!("expected zero or a positive integer, found {other}"),
+            )),
+        }
+
+        if let Some(quota) = secret.get("quota") {
+            if let Err(e) = quota.clone().try_into::<Quota>() {
+                problems.push((
+                    format!("{location}.quota"),
+                    e.to_string().trim().replace('\n', " "),
+                ));
+            }
+        }
+
+        match secret.get("key") {
+            None => {}
+            Some(Value::String(key)) => {
+                plaintext.push((user.clone(), format!("{location}.key"), key.clone()))
+            }
+            Some(other) => problems.push((
+                format!("{location}.key"),
+                format!("expected a string, found {}", other.type_str()),
+            )),
+        }
+
+        match secret.get("key_hash") {
+            None => {}
+            Some(Value::String(hash)) => {
+                check_hash(&format!("{location}.key_hash"), hash, &mut problems);
+                hashes.push((user.clone(), format!("{location}.key_hash"), hash.clone()));
+            }
+            Some(other) => problems.push((
+                format!("{location}.key_hash"),
+                format!("expected a string, found {}", other.type_str()),
+            )),
+        }
+
+        let keys = match secret.get("keys") {
+            None => Table::new(),
+            Some(Value::Table(keys)) => keys.clone(),
+            Some(other) => {
+                problems.push((
+                    format!("{location}.keys"),
+                    format!("expected a table, found {}", other.type_str()),
+                ));
+                Table::new()
+            }
+        };
+
+        let mut usable = secret.contains_key("key") || secret.contains_key("key_hash");
+
+        for (name, key) in keys {
+            let location = format!("{location}.keys.{name}");
+
+            let key: Key = match key.try_into() {
+                Ok(key) => key,
+                Err(e) => {
+                    problems.push((location, e.to_string().trim().replace('\n', " ")));
+                    continue;
+                }
+            };
+
+            usable |= check_key(&location, &key, &mut problems);
+            hashes.push((user.clone(), format!("{location}.hash"), key.hash));
+        }
+
+        if !usable {
+            problems.push((location, "no key can create tunnels".to_string()));
+        }
+    }
+
+    for (user, secret) in stored.iter().flatten() {
+        let location = format!("storage.users.{user}");
+        let mut usable = false;
+
+        for (name, key) in &secret.keys {
+            let location = format!("{location}.keys.{name}");
+
+            usable |= check_key(&location, key, &mut problems);
+            hashes.push((user.clone(), format!("{location}.hash"), key.hash.clone()));
+        }
+
+        if !usable {
+            problems.push((location, "no key can create tunnels".to_string()));
+        }
+    }
+
+    for (i, (user, location, key)) in plaintext.iter().enumerate() {
+        for (other_user, other, other_key) in &plaintext[i + 1..] {
+            if user != other_user && key == other_key {
+                problems.push((location.clone(), format!("same secret as {other}")));
+            }
+        }
+
+        for (other_user, other, hash) in &hashes {
+            if user != other_user && verify_key(key, hash) {
+                problems.push((location.clone(), format!("same secret as {other}")));
+            }
+        }
+    }
+
+    for (i, (user, location, hash)) in hashes.iter().enumerate() {
+        for (other_user, other, other_hash) in &hashes[i + 1..] {
+            if user != other_user && hash == other_hash {
+                problems.push((location.clone(), format!("same secret as {other}")));
+            }
+        }
+    }
+
+    if let Some(reason) = &unchecked {
+        println!(
+            "{}: stored users not checked, {reason} that the edge upgrades when it starts",
+            path.display()
+        );
+    }
+
+    if problems.is_empty() {
+        println!("{}: ok", path.display());
+        return Ok(());
+    }
+
+    for (location, message) in &problems {
+        println!("{}: {location}: {message}", path.display());
+    }
+
+    bail!("{} problem(s) found in {}", problems.len(), path.display())
+}
+
+/// The users in the database, or `None` if its schema is older.
+fn stored_users(db: &Path) -> Result<Option<HashMap<String, Secret>>> {
+    match SqliteStorage::open_read_only(db)? {
+        Some(storage) => Ok(Some(storage.users()?)),
+        None => Ok(None),
+    }
+}
+
+/// Checks a key, returning whether it can create tunnels.
+fn check_key(location: &str, key: &Key, problems: &mut Vec<(String, String)>) -> bool {
+    check_hash(&format!("{location}.hash"), &key.hash, problems);
+
+    if key.is_expired() {
+        problems.push((location.to_string(), "expired".to_string()));
+        return false;
+    }
+
+    key.scopes.contains(&Scope::Tunnels)
+}
+
+fn check_hash(location: &str, hash: &str, problems: &mut Vec<(String, String)>) {
+    let parts = hash.split('$').collect::<Vec<_>>();
+
+    let valid = matches!(parts.as_slice(), ["sha256", salt, digest]
+        if !salt.is_empty()
+            && digest.len() == 64
+            && digest.chars().all(|c| c.is_ascii_hexdigit()));
+
+    if !valid {
+        problems.push((
+            location.to_string(),
+            "not a sha256$<salt>$<digest> hash".to_string(),
+        ));
+    }
+}
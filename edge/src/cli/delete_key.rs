@@ -0,0 +1,21 @@
+use anyhow::{anyhow, Result};
+use log::info;
+
+use crate::storage::Storage;
+
+pub fn delete_key(storage: &mut dyn Storage, user: String, key_name: String) -> Result<String> {
+    let name = user.to_lowercase();
+    let mut secret = storage
+        .user(&name)?
+        .ok_or_else(|| anyhow!("user not found"))?;
+
+    secret
+        .keys
+        .remove(&key_name)
+        .ok_or_else(|| anyhow!("key not found"))?;
+
+    storage.save_user(&name, &secret)?;
+
+    info!("key {key_name} deleted from user {user}");
+    Ok(String::new())
+}
@@ -1,23 +1,33 @@
 use anyhow::{anyhow, Result};
 use log::info;
 
-use crate::config::{write_config, Configuration};
+use crate::{auth::verify_key, storage::Storage};
 
-pub fn delete_user(cfg: &mut Configuration, name_or_key: String) -> Result<()> {
-    let secret = if cfg.secrets.contains_key(&name_or_key.to_lowercase()) {
-        name_or_key.to_lowercase()
-    } else {
-        cfg.secrets
-            .iter()
-            .find(|(name, _)| **name == name_or_key)
-            .map(|(name, _)| name.clone())
-            .ok_or_else(|| anyhow!("user not found"))?
-    };
+pub fn delete_user(storage: &mut dyn Storage, name_or_key: String) -> Result<String> {
+    let secret = find_user(storage, &name_or_key)?;
 
-    cfg.secrets.remove(&secret);
-
-    write_config(cfg)?;
+    storage.delete_user(&secret)?;
 
     info!("user {} deleted", secret);
-    Ok(())
+    Ok(String::new())
+}
+
+/// Name of the user with the given name or secret key.
+pub fn find_user(storage: &dyn Storage, name_or_key: &str) -> Result<String> {
+    let users = storage.users()?;
+
+    if users.contains_key(&name_or_key.to_lowercase()) {
+        return Ok(name_or_key.to_lowercase());
+    }
+
+    users
+        .iter()
+        .find(|(_, secret)| {
+            secret
+                .keys
+                .values()
+                .any(|key| verify_key(name_or_key, &key.hash))
+        })
+        .map(|(name, _)| name.clone())
+        .ok_or_else(|| anyhow!("user not found"))
 }
@@ -0,0 +1,33 @@
+use anyhow::{anyhow, bail, Result};
+use log::info;
+
+use crate::state::State;
+
+/// Closes a user's sessions, or a single one of them. Closed sessions answer
+/// renewals with 410 Gone, which makes their clients stop instead of
+/// reconnecting.
+pub fn kick_client(state: &mut State, user: String, session: Option<String>) -> Result<String> {
+    let listener_tx = state.listener_tx.clone();
+    let secret = state
+        .secrets
+        .get_mut(&user.to_lowercase())
+        .ok_or_else(|| anyhow!("user not found"))?;
+
+    let sessions = secret
+        .sessions
+        .iter()
+        .filter(|(id, s)| !s.closed && session.as_ref().is_none_or(|session| session == *id))
+        .map(|(id, _)| id.clone())
+        .collect::<Vec<_>>();
+
+    if sessions.is_empty() {
+        bail!("no open session found");
+    }
+
+    for session in &sessions {
+        secret.close_session(session, &listener_tx);
+        info!("session {session} of user {user} kicked");
+    }
+
+    Ok(format!("closed {} session(s)", sessions.len()))
+}
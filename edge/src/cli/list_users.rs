@@ -0,0 +1,38 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::{auth::mask_hash, storage::Storage};
+
+pub fn list_users(storage: &dyn Storage) -> Result<String> {
+    let users = storage.users()?;
+
+    if users.is_empty() {
+        return Ok("no users".to_string());
+    }
+
+    let mut users = users.iter().collect::<Vec<_>>();
+    users.sort_by_key(|(name, _)| *name);
+
+    let mut output = String::new();
+    writeln!(output, "{:<20} {:>12}  KEYS", "NAME", "MAX TUNNELS")?;
+
+    for (name, secret) in users {
+        let mut keys = secret
+            .keys
+            .iter()
+            .map(|(key_name, key)| format!("{key_name}={}", mask_hash(&key.hash)))
+            .collect::<Vec<_>>();
+        keys.sort();
+
+        writeln!(
+            output,
+            "{:<20} {:>12}  {}",
+            name,
+            secret.max_tunnels,
+            keys.join(", ")
+        )?;
+    }
+
+    Ok(output)
+}
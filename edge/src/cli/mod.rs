@@ -1,8 +1,53 @@
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
+use anyhow::{bail, Result};
+use clap::{Args, Parser, Subcommand};
+use logging::LogFormat;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    audit::{self, AuditEvent, LOCAL_SOURCE},
+    config::{
+        AccessLogConfig, Configuration, QuotaPeriod, Scope, DEFAULT_CONFIG_PATH, DEFAULT_KEY_NAME,
+    },
+    storage::Storage,
+};
+
+use self::{
+    add_key::add_key,
+    add_user::add_user,
+    delete_key::delete_key,
+    delete_user::{delete_user, find_user},
+    list_users::list_users,
+    rotate_key::rotate_key,
+    set_certificate::set_certificate,
+    set_limits::set_limits,
+    show_user::show_user,
+    usage::usage,
+};
+
+pub mod add_key;
 pub mod add_user;
+#[cfg(unix)]
+pub mod admin;
+pub mod check_config;
+pub mod delete_key;
 pub mod delete_user;
+#[cfg(unix)]
+pub mod kick_client;
+pub mod list_users;
+pub mod rotate_key;
 pub mod serve;
+pub mod set_certificate;
+pub mod set_limits;
+pub mod show_user;
+#[cfg(unix)]
+pub mod stats;
+#[cfg(unix)]
+pub mod tunnels;
+pub mod usage;
+pub mod verify_audit;
 
 #[derive(Parser, Debug)]
 #[command(name = "fast-reverse-proxy")]
@@ -10,14 +55,95 @@ pub mod serve;
 #[command(about = "A blazing fast ngrok alternative written in Rust")]
 #[command(propagate_version = true)]
 pub struct Cli {
+    /// Path to the configuration file
+    #[arg(long, global = true, env = "EDGE_CONFIG", default_value = DEFAULT_CONFIG_PATH)]
+    pub config: PathBuf,
+
+    /// Format of the log output
+    #[arg(
+        long,
+        global = true,
+        env = "EDGE_LOG_FORMAT",
+        value_enum,
+        default_value_t
+    )]
+    pub log_format: LogFormat,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
-#[derive(Subcommand, Debug)]
+/// Settings that override the configuration file while serving. They are
+/// never written back, which keeps secrets and deployment details out of it.
+#[derive(Args, Debug, Serialize, Deserialize)]
+pub struct ServeOverrides {
+    /// Port to listen on
+    #[arg(long, env = "EDGE_PORT")]
+    pub port: Option<u16>,
+    /// Seconds a client session survives without renewing its lease
+    #[arg(long, env = "EDGE_SESSION_LEASE")]
+    pub session_lease: Option<u64>,
+    /// Seconds in-flight connections get to finish when shutting down
+    #[arg(long, env = "EDGE_SHUTDOWN_GRACE")]
+    pub shutdown_grace: Option<u64>,
+    /// File to write the access log to instead of the regular log
+    #[arg(long, env = "EDGE_ACCESS_LOG")]
+    pub access_log: Option<PathBuf>,
+}
+
+/// Limits changed by `set-limits`. Passing 0 removes any limit but
+/// `max_tunnels`.
+#[derive(Args, Debug, Serialize, Deserialize)]
+pub struct LimitChanges {
+    /// Maximum number of tunnels the user may open
+    #[arg(long)]
+    pub max_tunnels: Option<usize>,
+    /// Maximum number of visitors relayed at the same time
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+    /// Maximum number of idle HolePunch workers
+    #[arg(long)]
+    pub max_workers: Option<usize>,
+    /// Bytes that may be relayed per quota period
+    #[arg(long)]
+    pub quota_bytes: Option<u64>,
+    /// Visitors that may connect per quota period
+    #[arg(long)]
+    pub quota_connections: Option<u64>,
+    /// How often the quotas are reset
+    #[arg(long)]
+    pub quota_period: Option<QuotaPeriod>,
+}
+
+impl ServeOverrides {
+    pub fn apply(&self, cfg: &mut Configuration) {
+        if let Some(port) = self.port {
+            cfg.port = port;
+        }
+        if let Some(session_lease) = self.session_lease {
+            cfg.session_lease = session_lease;
+        }
+        if let Some(shutdown_grace) = self.shutdown_grace {
+            cfg.shutdown_grace = shutdown_grace;
+        }
+        if let Some(path) = &self.access_log {
+            match &mut cfg.access_log {
+                Some(access_log) => access_log.path = path.clone(),
+                None => cfg.access_log = Some(AccessLogConfig::new(path.clone())),
+            }
+        }
+    }
+}
+
+/// Commands other than `serve` and `check-config` are sent to the running
+/// edge over its admin socket, so they apply live.
+#[derive(Subcommand, Debug, Serialize, Deserialize)]
 pub enum Commands {
     /// Start the edge server and listen for incoming connections
-    Serve {},
+    Serve {
+        #[command(flatten)]
+        overrides: ServeOverrides,
+    },
     /// Add a new user and generate a secret key for them
     AddUser {
         name: String,
@@ -25,4 +151,182 @@ pub enum Commands {
     },
     /// Delete an existing user by their name or secret key
     DeleteUser { name_or_key: String },
+    /// List all users with their limits and masked keys
+    ListUsers {},
+    /// Show a user's limits and keys
+    ShowUser { user: String },
+    /// Change a user's limits in place
+    SetLimits {
+        user: String,
+        #[command(flatten)]
+        limits: LimitChanges,
+    },
+    /// Generate an additional named key for an existing user
+    AddKey {
+        user: String,
+        name: String,
+        /// Scopes granted to the key, defaults to all of them but admin
+        #[arg(long, value_delimiter = ',')]
+        scopes: Vec<Scope>,
+        /// Seconds until the key expires
+        #[arg(long)]
+        expires_in: Option<u64>,
+    },
+    /// Revoke one of a user's keys
+    DeleteKey { user: String, name: String },
+    /// Replace a user's key, keeping the old one valid for a grace period
+    RotateKey {
+        user: String,
+        #[arg(default_value = DEFAULT_KEY_NAME)]
+        name: String,
+        /// Seconds the old key stays valid
+        #[arg(long, default_value_t = 86400)]
+        grace: u64,
+    },
+    /// Let a user log in with a client certificate, or stop them from it
+    SetCertificate {
+        user: String,
+        /// Scopes granted to the certificate, defaults to all of them but admin
+        #[arg(long, value_delimiter = ',', conflicts_with = "disable")]
+        scopes: Vec<Scope>,
+        /// Refuse the user's certificates
+        #[arg(long)]
+        disable: bool,
+    },
+    /// Validate the configuration file and report every problem
+    CheckConfig {},
+    /// List the running edge's tunnels
+    Tunnels {},
+    /// Close a user's sessions on the running edge, stopping their clients
+    KickClient {
+        user: String,
+        /// Only close this session
+        session: Option<String>,
+    },
+    /// Show the running edge's usage
+    Stats {},
+    /// Check that no record of the audit log was modified or removed
+    VerifyAudit {},
+    /// Show the traffic relayed per user and day
+    Usage {
+        /// Only show this user's traffic
+        user: Option<String>,
+        /// Number of days to show, including today
+        #[arg(long, default_value_t = 30)]
+        days: u64,
+    },
+}
+
+impl Commands {
+    /// Whether the command only makes sense against a running edge.
+    pub fn needs_server(&self) -> bool {
+        matches!(
+            self,
+            Commands::Tunnels {} | Commands::KickClient { .. } | Commands::Stats {}
+        )
+    }
+}
+
+impl Commands {
+    /// Action and parameters recorded in the audit log, for commands that
+    /// change users. Secret keys are never part of them.
+    pub fn audit_action(&self, storage: &dyn Storage) -> Option<(&'static str, Value)> {
+        match self {
+            Commands::AddUser { name, max_tunnels } => Some((
+                "user.add",
+                json!({"user": name.to_lowercase(), "max_tunnels": max_tunnels}),
+            )),
+            Commands::DeleteUser { name_or_key } => Some((
+                "user.delete",
+                json!({"user": find_user(storage, name_or_key).ok()}),
+            )),
+            Commands::SetLimits { user, limits } => Some((
+                "user.set_limits",
+                json!({"user": user.to_lowercase(), "limits": limits}),
+            )),
+            Commands::AddKey {
+                user,
+                name,
+                scopes,
+                expires_in,
+            } => Some((
+                "key.add",
+                json!({"user": user.to_lowercase(), "name": name, "scopes": scopes, "expires_in": expires_in}),
+            )),
+            Commands::DeleteKey { user, name } => Some((
+                "key.delete",
+                json!({"user": user.to_lowercase(), "name": name}),
+            )),
+            Commands::SetCertificate {
+                user,
+                scopes,
+                disable,
+            } => Some((
+                "user.set_certificate",
+                json!({"user": user.to_lowercase(), "scopes": scopes, "disable": disable}),
+            )),
+            Commands::RotateKey { user, name, grace } => Some((
+                "key.rotate",
+                json!({"user": user.to_lowercase(), "name": name, "grace": grace}),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Appends the audit record of a command run through the CLI, if it is one
+/// that gets recorded.
+pub fn audit_command<T>(action: Option<(&str, Value)>, result: &Result<T>) {
+    let Some((action, params)) = action else {
+        return;
+    };
+
+    let outcome = match result {
+        Ok(_) => "ok".to_string(),
+        Err(e) => e.to_string(),
+    };
+
+    audit::record(AuditEvent {
+        user: None,
+        key: None,
+        source: LOCAL_SOURCE,
+        action,
+        params,
+        outcome: &outcome,
+    });
+}
+
+/// Runs a command that reads or edits the storage, returning what should
+/// be shown to the user.
+pub fn run_storage_command(storage: &mut dyn Storage, command: Commands) -> Result<String> {
+    let action = command.audit_action(storage);
+    let result = execute_storage_command(storage, command);
+
+    audit_command(action, &result);
+    result
+}
+
+fn execute_storage_command(storage: &mut dyn Storage, command: Commands) -> Result<String> {
+    match command {
+        Commands::AddUser { name, max_tunnels } => add_user(storage, name, max_tunnels),
+        Commands::DeleteUser { name_or_key } => delete_user(storage, name_or_key),
+        Commands::ListUsers {} => list_users(storage),
+        Commands::ShowUser { user } => show_user(storage, user),
+        Commands::SetLimits { user, limits } => set_limits(storage, user, limits),
+        Commands::AddKey {
+            user,
+            name,
+            scopes,
+            expires_in,
+        } => add_key(storage, user, name, scopes, expires_in),
+        Commands::DeleteKey { user, name } => delete_key(storage, user, name),
+        Commands::RotateKey { user, name, grace } => rotate_key(storage, user, name, grace),
+        Commands::SetCertificate {
+            user,
+            scopes,
+            disable,
+        } => set_certificate(storage, user, scopes, disable),
+        Commands::Usage { user, days } => usage(storage, user, days),
+        command => bail!("{command:?} does not use the storage"),
+    }
 }
@@ -0,0 +1,56 @@
+use anyhow::{anyhow, bail, Result};
+use log::info;
+
+use crate::{
+    auth::{generate_key, hash_key, now},
+    config::Key,
+    storage::Storage,
+};
+
+/// Replaces a key with a freshly generated one. The old key is kept as
+/// `<name>-previous` until the grace period is over, and the key can't be
+/// rotated again before then. Keys that have expired are removed.
+pub fn rotate_key(
+    storage: &mut dyn Storage,
+    user: String,
+    key_name: String,
+    grace: u64,
+) -> Result<String> {
+    let name = user.to_lowercase();
+    let mut secret = storage
+        .user(&name)?
+        .ok_or_else(|| anyhow!("user not found"))?;
+
+    let previous_name = format!("{key_name}-previous");
+
+    if secret
+        .keys
+        .get(&previous_name)
+        .is_some_and(|previous| !previous.is_expired())
+    {
+        bail!("key {previous_name} is still valid, delete it to rotate {key_name} again");
+    }
+
+    let mut old = secret
+        .keys
+        .remove(&key_name)
+        .ok_or_else(|| anyhow!("key not found"))?;
+
+    secret.keys.retain(|_, key| !key.is_expired());
+
+    let key = generate_key();
+    let new = Key::new(hash_key(&key), old.scopes.clone(), old.expires_at);
+
+    let grace_ends = now() + grace;
+    old.expires_at = Some(old.expires_at.map_or(grace_ends, |e| e.min(grace_ends)));
+
+    secret.keys.insert(previous_name, old);
+    secret.keys.insert(key_name.clone(), new);
+
+    storage.save_user(&name, &secret)?;
+
+    info!("key {key_name} of user {user} rotated, old key valid for {grace}s");
+
+    // Only the hash is stored, so this is the one chance to see the key.
+    Ok(key)
+}
@@ -1,29 +1,104 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::TcpListener, sync::Arc, time::Duration};
 
-use actix_web::{http::KeepAlive, web, App, HttpServer};
-use anyhow::Result;
-use log::info;
-use tokio::sync::{mpsc::unbounded_channel, Mutex};
+use actix_web::{dev::Service, http::KeepAlive, web, App, HttpServer};
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use tokio::{
+    select,
+    signal::ctrl_c,
+    sync::{mpsc::unbounded_channel, Mutex},
+    time::{interval, sleep, Instant},
+};
 
+#[cfg(unix)]
+use crate::cli::admin;
+#[cfg(target_os = "linux")]
+use crate::upgrade;
 use crate::{
+    access_log::init_access_log,
     api,
+    audit::{flush_audit_log, init_audit_log, spawn_audit_writer},
+    bans::{self, AuthFailures},
     config::Configuration,
-    listener::{self, worker},
-    state::{Secret, State},
+    listener::{self, proxy, worker},
+    state::State,
+    storage::{open_storage, StorageTask},
+    tls,
 };
 
+/// The sockets the API and HolePunch workers are served on.
+pub struct Listeners {
+    pub api: TcpListener,
+    pub worker: TcpListener,
+}
+
+impl Listeners {
+    fn bind(port: u16) -> Result<Self> {
+        Ok(Self {
+            api: TcpListener::bind(("0.0.0.0", port))
+                .map_err(|e| anyhow!("failed to bind to port {port}: {e}"))?,
+            worker: TcpListener::bind("0.0.0.0:0")
+                .map_err(|e| anyhow!("failed to bind to port: {e}"))?,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            api: self.api.try_clone()?,
+            worker: self.worker.try_clone()?,
+        })
+    }
+}
+
 pub async fn serve(cfg: &'static mut Configuration) -> Result<()> {
+    if let Some(access_log) = &cfg.access_log {
+        init_access_log(access_log)?;
+    }
+
+    init_audit_log(cfg)?;
+    spawn_audit_writer();
+    info!("writing audit log to {}", cfg.audit_log_path().display());
+
+    // Started by an edge being upgraded, which passes on its sockets.
+    #[cfg(target_os = "linux")]
+    let (listeners, takeover) = match upgrade::take_over()? {
+        Some((listeners, takeover)) => (listeners, Some(takeover)),
+        None => (Listeners::bind(cfg.port)?, None),
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    let listeners = Listeners::bind(cfg.port)?;
+
+    // Kept to hand them over to the next upgrade.
+    #[cfg(target_os = "linux")]
+    let sockets = listeners.try_clone()?;
+
     info!("starting listener...");
 
     let (tx, rx) = unbounded_channel();
 
-    info!("booting with {} secrets...", cfg.secrets.len());
+    let storage = open_storage(cfg)?;
+    let users = storage.users()?;
+    let reservations = storage.reservations().unwrap_or_else(|e| {
+        error!("failed to restore tunnel reservations: {e}");
+        vec![]
+    });
+
+    info!("booting with {} secrets...", users.len());
+
+    let auth_failures = Arc::new(AuthFailures::new(cfg.auth_ban.clone()));
+    let tls_config = cfg.tls.clone();
 
     let state = State {
         cfg,
         worker_port: None,
         listener_tx: tx,
         secrets: HashMap::new(),
+        draining: false,
+        storage: StorageTask::spawn(storage),
+        pending_traffic: HashMap::new(),
+        auth_failures: auth_failures.clone(),
     };
 
     let state = Arc::new(Mutex::new(state));
@@ -35,44 +110,273 @@ pub async fn serve(cfg: &'static mut Configuration) -> Result<()> {
         });
     }
 
-    let worker_port = worker::start_worker_server(state.clone()).await?;
+    let worker_port = listeners.worker.local_addr()?.port();
+    let worker_task =
+        worker::start_worker_server(listeners.worker, state.clone(), tls_config.as_ref())?;
 
     {
         let mut state = state.lock().await;
 
         state.worker_port = Some(worker_port);
 
-        cfg.secrets.iter().for_each(|(_, secret)| {
-            state.secrets.insert(
-                secret.key.clone(),
-                Secret {
-                    secret: secret.key.clone(),
-                    max_tunnels: secret.max_tunnels,
-                    active_tunnels: vec![],
-                    workers: vec![],
-                },
-            );
+        state.sync_secrets(&users);
+        state.restore_reservations(reservations);
+    }
+
+    #[cfg(target_os = "linux")]
+    let parent = match takeover {
+        Some(takeover) => Some(upgrade::adopt(&state, takeover).await),
+        None => None,
+    };
+
+    #[cfg(unix)]
+    let (admin_path, admin_inode) = {
+        let admin_path = admin::socket_path(&cfg.path);
+
+        #[cfg(target_os = "linux")]
+        let takeover = parent.is_some();
+        #[cfg(not(target_os = "linux"))]
+        let takeover = false;
+
+        let (listener, admin_inode) = admin::bind_admin_socket(&admin_path, takeover).await?;
+
+        info!("admin socket listening on {}", admin_path.display());
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            admin::serve_admin(listener, state).await;
+        });
+
+        (admin_path, admin_inode)
+    };
+
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            reap_sessions(state).await;
         });
     }
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(state.clone()))
-            .service(api::health)
-            .service(api::check_authorization)
-            .service(api::connect)
-            .service(api::goodbye)
-            .service(api::edge::create_edge)
-            .service(api::edge::delete_edge)
-            .service(api::edge::delete_edges)
-    })
-    .workers(4)
-    .keep_alive(KeepAlive::Timeout(Duration::from_secs(900)))
-    .bind(("0.0.0.0", cfg.port))?
-    .run()
-    .await?;
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            flush_traffic(state).await;
+        });
+    }
+
+    {
+        let auth_failures = auth_failures.clone();
+        tokio::spawn(async move {
+            sweep_auth_failures(auth_failures).await;
+        });
+    }
+
+    let server = {
+        let state = state.clone();
+        let identity = tls_config.as_ref().map(|tls| tls.identity);
+
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .app_data(web::Data::new(auth_failures.clone()))
+                .wrap_fn(|req, srv| {
+                    let response = match api::refuse_banned(&req) {
+                        Some(refusal) => Err(req.into_response(refusal)),
+                        None => Ok(srv.call(req)),
+                    };
+
+                    async move {
+                        let response = match response {
+                            Ok(response) => response.await?,
+                            Err(refusal) => return Ok(refusal),
+                        };
+
+                        api::check_auth(&response).await;
+                        Ok(response)
+                    }
+                })
+                .service(api::health)
+                .service(api::check_authorization)
+                .service(api::connect)
+                .service(api::goodbye)
+                .service(api::session::open_session)
+                .service(api::session::renew_session)
+                .service(api::edge::list_edges)
+                .service(api::edge::get_edge)
+                .service(api::edge::create_edge)
+                .service(api::edge::update_edge)
+                .service(api::edge::delete_edge)
+                .service(api::edge::delete_edges)
+                .service(api::quota::get_quota)
+                .service(api::admin::dashboard)
+                .service(api::admin::overview)
+                .service(api::admin::audit_log)
+                .service(api::admin::list_bans)
+                .service(api::admin::lift_ban)
+        })
+        .workers(4)
+        .keep_alive(KeepAlive::Timeout(Duration::from_secs(900)))
+        // Shutdown is handled below, so the API stays up while draining.
+        .disable_signals()
+        .on_connect(move |conn, extensions| {
+            if let Some(identity) = identity {
+                tls::keep_client_certificate(conn, extensions, identity);
+            }
+        });
+
+        match &tls_config {
+            Some(tls_config) => {
+                info!("serving the API and workers over TLS");
+                server.listen_openssl(listeners.api, tls::acceptor(tls_config)?)?
+            }
+            None => server.listen(listeners.api)?,
+        }
+        .run()
+    };
+
+    #[cfg(target_os = "linux")]
+    if let Some(parent) = parent {
+        upgrade::confirm(parent)?;
+        info!("took over from the previous edge");
+    }
+
+    {
+        let handle = server.handle();
+        let grace = Duration::from_secs(cfg.shutdown_grace);
+
+        #[cfg(target_os = "linux")]
+        let upgraded = upgrade::wait_for_upgrade(state.clone(), sockets, cfg.path.clone());
+        #[cfg(not(target_os = "linux"))]
+        let upgraded = std::future::pending::<()>();
+
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            select! {
+                _ = shutdown_signal() => {}
+                _ = upgraded => {
+                    // The new edge accepts on the same sockets from now on.
+                    handle.pause().await;
+                    worker_task.abort();
+                }
+            }
+
+            drain(state, grace).await;
+            handle.stop(true).await;
+        });
+    }
+
+    server.await?;
 
     info!("shutting down...");
 
+    State::flush_traffic(&state).await;
+    flush_audit_log().await;
+
+    #[cfg(unix)]
+    admin::remove_admin_socket(&admin_path, admin_inode);
+
     Ok(())
 }
+
+/// Resolves once the edge is asked to stop, through SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use log::error;
+        use tokio::{
+            select,
+            signal::unix::{signal, SignalKind},
+        };
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                select! {
+                    _ = terminate.recv() => {}
+                    _ = ctrl_c() => {}
+                }
+            }
+            Err(e) => {
+                error!("failed to listen for SIGTERM: {e}");
+                let _ = ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = ctrl_c().await;
+}
+
+/// Stops accepting visitors and waits for the connections being relayed to
+/// finish, for at most the grace period.
+async fn drain(state: Arc<Mutex<State>>, grace: Duration) {
+    info!(
+        "shutting down, waiting up to {}s for connections to finish...",
+        grace.as_secs()
+    );
+
+    state.lock().await.start_draining();
+
+    let deadline = Instant::now() + grace;
+
+    loop {
+        let active = proxy::active_connections();
+
+        if active == 0 {
+            info!("all connections finished");
+            break;
+        }
+
+        if Instant::now() >= deadline {
+            warn!("grace period is over, dropping {active} connection(s)");
+            break;
+        }
+
+        sleep(Duration::from_millis(250)).await;
+    }
+}
+
+/// Periodically writes the traffic relayed for each user to the storage.
+async fn flush_traffic(state: Arc<Mutex<State>>) {
+    let mut interval = interval(Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+        State::flush_traffic(&state).await;
+    }
+}
+
+/// Periodically forgets expired authentication failures and bans.
+async fn sweep_auth_failures(auth_failures: Arc<AuthFailures>) {
+    let mut interval = interval(bans::SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        auth_failures.sweep();
+    }
+}
+
+/// Periodically ends the sessions of clients that stopped renewing their lease.
+async fn reap_sessions(state: Arc<Mutex<State>>) {
+    let mut interval = interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+
+        let mut state = state.lock().await;
+
+        // Clients are told to move elsewhere while draining, so their leases
+        // lapse, but the connections they are relaying are let finish.
+        if state.draining {
+            continue;
+        }
+
+        let listener_tx = state.listener_tx.clone();
+
+        for secret in state.secrets.values_mut() {
+            for session in secret.end_expired_sessions(&listener_tx) {
+                info!("session {session} of user {} expired", secret.name);
+            }
+        }
+    }
+}
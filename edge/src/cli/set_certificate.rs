@@ -0,0 +1,33 @@
+use anyhow::{anyhow, Result};
+use log::info;
+
+use crate::{config::Scope, storage::Storage};
+
+pub fn set_certificate(
+    storage: &mut dyn Storage,
+    user: String,
+    scopes: Vec<Scope>,
+    disable: bool,
+) -> Result<String> {
+    let name = user.to_lowercase();
+    let mut secret = storage
+        .user(&name)?
+        .ok_or_else(|| anyhow!("user not found"))?;
+
+    secret.certificate_scopes = if disable {
+        None
+    } else if scopes.is_empty() {
+        Some(Scope::defaults())
+    } else {
+        Some(scopes)
+    };
+
+    storage.save_user(&name, &secret)?;
+
+    match &secret.certificate_scopes {
+        Some(scopes) => info!("user {user} can log in with a certificate ({scopes:?})"),
+        None => info!("user {user} can no longer log in with a certificate"),
+    }
+
+    Ok(String::new())
+}
@@ -0,0 +1,58 @@
+use anyhow::{anyhow, bail, Result};
+use log::info;
+
+use crate::storage::Storage;
+
+use super::LimitChanges;
+
+pub fn set_limits(storage: &mut dyn Storage, user: String, limits: LimitChanges) -> Result<String> {
+    let name = user.to_lowercase();
+    let mut secret = storage
+        .user(&name)?
+        .ok_or_else(|| anyhow!("user not found"))?;
+
+    let LimitChanges {
+        max_tunnels,
+        max_connections,
+        max_workers,
+        quota_bytes,
+        quota_connections,
+        quota_period,
+    } = limits;
+
+    let mut changes = vec![];
+
+    if let Some(max_tunnels) = max_tunnels {
+        secret.max_tunnels = max_tunnels;
+        changes.push(format!("max_tunnels={max_tunnels}"));
+    }
+    if let Some(max_connections) = max_connections {
+        secret.quota.max_connections = Some(max_connections).filter(|max| *max > 0);
+        changes.push(format!("max_connections={max_connections}"));
+    }
+    if let Some(max_workers) = max_workers {
+        secret.quota.max_workers = Some(max_workers).filter(|max| *max > 0);
+        changes.push(format!("max_workers={max_workers}"));
+    }
+    if let Some(bytes) = quota_bytes {
+        secret.quota.bytes = Some(bytes).filter(|max| *max > 0);
+        changes.push(format!("quota_bytes={bytes}"));
+    }
+    if let Some(connections) = quota_connections {
+        secret.quota.connections = Some(connections).filter(|max| *max > 0);
+        changes.push(format!("quota_connections={connections}"));
+    }
+    if let Some(period) = quota_period {
+        secret.quota.period = period;
+        changes.push(format!("quota_period={period:?}"));
+    }
+
+    if changes.is_empty() {
+        bail!("no limits given");
+    }
+
+    storage.save_user(&name, &secret)?;
+
+    info!("limits of user {user} updated ({})", changes.join(", "));
+    Ok(String::new())
+}
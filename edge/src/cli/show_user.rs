@@ -0,0 +1,78 @@
+use std::fmt::Write;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    auth::{mask_hash, now},
+    storage::Storage,
+};
+
+pub fn show_user(storage: &dyn Storage, user: String) -> Result<String> {
+    let name = user.to_lowercase();
+    let secret = storage
+        .user(&name)?
+        .ok_or_else(|| anyhow!("user not found"))?;
+
+    let mut output = String::new();
+    writeln!(output, "name:            {name}")?;
+    writeln!(output, "max_tunnels:     {}", secret.max_tunnels)?;
+
+    let quota = &secret.quota;
+    let limit = |max: Option<u64>| max.map_or("unlimited".to_string(), |max| max.to_string());
+    writeln!(
+        output,
+        "max_connections: {}",
+        limit(quota.max_connections.map(|max| max as u64))
+    )?;
+    writeln!(
+        output,
+        "max_workers:     {}",
+        limit(quota.max_workers.map(|max| max as u64))
+    )?;
+    writeln!(
+        output,
+        "quota:           {} bytes, {} connections per {:?}",
+        limit(quota.bytes),
+        limit(quota.connections),
+        quota.period
+    )?;
+    let certificate = match &secret.certificate_scopes {
+        Some(scopes) => scopes
+            .iter()
+            .map(|scope| format!("{scope:?}"))
+            .collect::<Vec<_>>()
+            .join(","),
+        None => "disabled".to_string(),
+    };
+    writeln!(output, "certificate:     {certificate}")?;
+    writeln!(output, "keys:")?;
+
+    let mut keys = secret.keys.iter().collect::<Vec<_>>();
+    keys.sort_by_key(|(key_name, _)| *key_name);
+
+    for (key_name, key) in keys {
+        let scopes = key
+            .scopes
+            .iter()
+            .map(|scope| format!("{scope:?}"))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let expiry = match key.expires_at {
+            None => "never expires".to_string(),
+            Some(_) if key.is_expired() => "expired".to_string(),
+            Some(expires_at) => format!("expires in {}s", expires_at - now()),
+        };
+
+        writeln!(
+            output,
+            "  {:<20} {:<12} {:<24} {}",
+            key_name,
+            mask_hash(&key.hash),
+            scopes,
+            expiry
+        )?;
+    }
+
+    Ok(output)
+}
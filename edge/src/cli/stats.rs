@@ -0,0 +1,44 @@
+use std::{fmt::Write, sync::atomic::Ordering};
+
+use anyhow::Result;
+
+use crate::state::State;
+
+pub fn stats(state: &State) -> Result<String> {
+    let secrets = state.secrets.values();
+
+    let sessions = secrets
+        .clone()
+        .flat_map(|secret| secret.sessions.values())
+        .filter(|session| !session.closed);
+    let tunnels = secrets
+        .clone()
+        .flat_map(|secret| secret.active_tunnels.iter());
+
+    let mut output = String::new();
+    writeln!(output, "users:              {}", state.secrets.len())?;
+    writeln!(output, "sessions:           {}", sessions.clone().count())?;
+    writeln!(
+        output,
+        "idle workers:       {}",
+        sessions.map(|session| session.workers.len()).sum::<usize>()
+    )?;
+    writeln!(output, "tunnels:            {}", tunnels.clone().count())?;
+    writeln!(
+        output,
+        "active connections: {}",
+        tunnels
+            .clone()
+            .map(|t| t.stats.active_connections.load(Ordering::Relaxed))
+            .sum::<usize>()
+    )?;
+    writeln!(
+        output,
+        "total connections:  {}",
+        tunnels
+            .map(|t| t.stats.total_connections.load(Ordering::Relaxed))
+            .sum::<u64>()
+    )?;
+
+    Ok(output)
+}
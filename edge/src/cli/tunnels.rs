@@ -0,0 +1,74 @@
+use std::{fmt::Write, sync::atomic::Ordering};
+
+use anyhow::Result;
+
+use crate::state::State;
+
+pub fn tunnels(state: &State) -> Result<String> {
+    let mut tunnels = state
+        .secrets
+        .values()
+        .flat_map(|secret| secret.active_tunnels.iter().map(move |t| (secret, t)))
+        .collect::<Vec<_>>();
+
+    // Reserved ports of tunnels whose client hasn't come back yet.
+    let mut offline = state
+        .secrets
+        .values()
+        .flat_map(|secret| {
+            secret
+                .reservations
+                .iter()
+                .filter(|(name, _)| !secret.active_tunnels.iter().any(|t| &&t.name == name))
+                .map(move |(name, reservation)| (secret, name, reservation))
+        })
+        .collect::<Vec<_>>();
+
+    if tunnels.is_empty() && offline.is_empty() {
+        return Ok("no active tunnels".to_string());
+    }
+
+    tunnels.sort_by_key(|(secret, tunnel)| (&secret.name, &tunnel.name));
+    offline.sort_by_key(|(secret, name, _)| (&secret.name, *name));
+
+    let mut output = String::new();
+    writeln!(
+        output,
+        "{:<16} {:<20} {:>6} {:<24} {:<10} {:<10} {:<10} {:>8} {:>8}",
+        "USER", "NAME", "PORT", "TARGET", "PROTOCOL", "MODE", "SESSION", "ACTIVE", "TOTAL"
+    )?;
+
+    for (secret, tunnel) in tunnels {
+        writeln!(
+            output,
+            "{:<16} {:<20} {:>6} {:<24} {:<10} {:<10} {:<10} {:>8} {:>8}",
+            secret.name,
+            tunnel.name,
+            tunnel.port,
+            tunnel.spec.target,
+            format!("{:?}", tunnel.spec.protocol),
+            format!("{:?}", tunnel.spec.mode),
+            tunnel.session.get(..8).unwrap_or(&tunnel.session),
+            tunnel.stats.active_connections.load(Ordering::Relaxed),
+            tunnel.stats.total_connections.load(Ordering::Relaxed),
+        )?;
+    }
+
+    for (secret, name, reservation) in offline {
+        writeln!(
+            output,
+            "{:<16} {:<20} {:>6} {:<24} {:<10} {:<10} {:<10} {:>8} {:>8}",
+            secret.name,
+            name,
+            reservation.port,
+            "-",
+            format!("{:?}", reservation.protocol),
+            format!("{:?}", reservation.mode),
+            "offline",
+            "-",
+            "-",
+        )?;
+    }
+
+    Ok(output)
+}
@@ -0,0 +1,41 @@
+use std::{
+    fmt::Write,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use humantime::format_rfc3339;
+
+use crate::storage::{days_ago, Storage};
+
+pub fn usage(storage: &dyn Storage, user: Option<String>, days: u64) -> Result<String> {
+    let user = user.map(|user| user.to_lowercase());
+    let traffic = storage.traffic(user.as_deref(), days_ago(days.saturating_sub(1)))?;
+
+    if traffic.is_empty() {
+        return Ok("no traffic recorded".to_string());
+    }
+
+    let mut output = String::new();
+    writeln!(
+        output,
+        "{:<10} {:<20} {:>16} {:>16} {:>12}",
+        "DATE", "USER", "BYTES IN", "BYTES OUT", "CONNECTIONS"
+    )?;
+
+    for t in traffic {
+        let date = format_rfc3339(UNIX_EPOCH + Duration::from_secs(t.day)).to_string();
+
+        writeln!(
+            output,
+            "{:<10} {:<20} {:>16} {:>16} {:>12}",
+            &date[..10],
+            t.user,
+            t.traffic.bytes_in,
+            t.traffic.bytes_out,
+            t.traffic.connections
+        )?;
+    }
+
+    Ok(output)
+}
@@ -0,0 +1,20 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::{audit::verify, config::load_config};
+
+/// Checks the audit log's chain without the edge, printing the number of
+/// records and the last hash to compare with a copy kept elsewhere.
+pub fn verify_audit(config_path: &Path) -> Result<()> {
+    let cfg = load_config(config_path)?;
+    let path = cfg.audit_log_path();
+
+    let (records, hash) = verify(&cfg)?;
+    println!(
+        "{}: {records} record(s), chain intact, last hash {hash}",
+        path.display()
+    );
+
+    Ok(())
+}
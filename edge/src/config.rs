@@ -1,35 +1,407 @@
-use std::{collections::HashMap, fs::read_to_string};
+use std::{
+    collections::HashMap,
+    fs::{self, read_to_string, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use log::warn;
 use serde::{Deserialize, Serialize};
 use toml::from_str;
 
-const CONFIG_PATH: &str = if cfg!(debug_assertions) {
-    "edge/config.toml"
-} else {
-    "config.toml"
+use crate::{
+    auth::{hash_key, now},
+    storage::{month_start, today},
 };
 
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+pub const DEFAULT_KEY_NAME: &str = "default";
+
 #[derive(Deserialize, Serialize)]
 pub struct Configuration {
+    /// File the configuration was loaded from and is written back to.
+    #[serde(skip)]
+    pub path: PathBuf,
     pub port: u16,
+    /// Seconds a client session stays alive without being renewed.
+    #[serde(default = "default_session_lease")]
+    pub session_lease: u64,
+    /// Seconds in-flight connections get to finish when shutting down.
+    #[serde(default = "default_shutdown_grace")]
+    pub shutdown_grace: u64,
+    /// Separate file for access log entries, which go to the regular log otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_log: Option<AccessLogConfig>,
+    /// Append-only log of control-plane actions, `<config>.audit.jsonl` by
+    /// default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<PathBuf>,
+    /// Bytes after which the audit log moves on to a new file, keeping the
+    /// full one as `<audit log>.<n>`.
+    #[serde(
+        default = "default_audit_max_size",
+        skip_serializing_if = "is_default_audit_max_size"
+    )]
+    pub audit_max_size: u64,
+    /// Secret the audit log's chain is keyed with, `<config>.audit.key` by
+    /// default. It is created on first use, and should be kept where those
+    /// able to edit the log can't read it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_key: Option<PathBuf>,
+    /// When source IPs that keep failing to authenticate are banned.
+    #[serde(default, skip_serializing_if = "AuthBanConfig::is_default")]
+    pub auth_ban: AuthBanConfig,
+    /// Serves the API and the worker port over TLS, optionally accepting
+    /// client certificates in place of keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    /// Where users, reservations and traffic are kept.
+    #[serde(default, skip_serializing_if = "StorageConfig::is_toml")]
+    pub storage: StorageConfig,
+    /// Users, unless they are stored elsewhere.
+    #[serde(default)]
     pub secrets: HashMap<String, Secret>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct AuthBanConfig {
+    /// Failed authentications after which the source IP is banned.
+    #[serde(default = "default_auth_ban_max_failures")]
+    pub max_failures: u32,
+    /// Seconds after which past failures are forgotten.
+    #[serde(default = "default_auth_ban_window")]
+    pub window: u64,
+    /// Seconds a source IP stays banned.
+    #[serde(default = "default_auth_ban_duration")]
+    pub duration: u64,
+}
+
+impl Default for AuthBanConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: default_auth_ban_max_failures(),
+            window: default_auth_ban_window(),
+            duration: default_auth_ban_duration(),
+        }
+    }
+}
+
+impl AuthBanConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate chain of the edge.
+    pub cert: PathBuf,
+    /// PEM private key of the edge.
+    pub key: PathBuf,
+    /// PEM CA certificates client certificates must be issued by. Client
+    /// certificates are not asked for without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ca: Option<PathBuf>,
+    /// PEM revocation lists of the client CA. Revoked client certificates
+    /// fail the handshake.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crl: Option<PathBuf>,
+    /// Which part of a client certificate holds the name of its user.
+    #[serde(default)]
+    pub identity: CertificateIdentity,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+pub enum CertificateIdentity {
+    /// The common name of the subject.
+    #[default]
+    CommonName,
+    /// The DNS names, email addresses and URIs of the subject alternative
+    /// name extension. The first one naming a user is used.
+    SubjectAltName,
+}
+
+impl Configuration {
+    pub fn audit_log_path(&self) -> PathBuf {
+        self.audit_log
+            .clone()
+            .unwrap_or_else(|| with_suffix(&self.path, "audit.jsonl"))
+    }
+
+    pub fn audit_key_path(&self) -> PathBuf {
+        self.audit_key
+            .clone()
+            .unwrap_or_else(|| with_suffix(&self.path, "audit.key"))
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// Users in the configuration file, reservations and traffic in JSON
+    /// files next to it.
+    #[default]
+    Toml,
+    /// A SQLite database, which scales to many users.
+    Sqlite { path: PathBuf },
+}
+
+impl StorageConfig {
+    fn is_toml(&self) -> bool {
+        matches!(self, StorageConfig::Toml)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    /// Size in bytes after which the file is rotated.
+    #[serde(default = "default_access_log_max_size")]
+    pub max_size: u64,
+    /// Number of rotated files kept next to the current one.
+    #[serde(default = "default_access_log_keep")]
+    pub keep: usize,
+}
+
+impl AccessLogConfig {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_size: default_access_log_max_size(),
+            keep: default_access_log_keep(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Secret {
     pub max_tunnels: usize,
-    pub key: String,
+    /// Plaintext key from older configs, moved into `keys` on load.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Single hashed key from older configs, moved into `keys` on load.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_hash: Option<String>,
+    #[serde(default)]
+    pub keys: HashMap<String, Key>,
+    #[serde(default, skip_serializing_if = "Quota::is_unlimited")]
+    pub quota: Quota,
+    /// Scopes granted to the user's client certificates. Certificates can't
+    /// be used to log in without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub certificate_scopes: Option<Vec<Scope>>,
+}
+
+/// Limits on a user's visitors besides `max_tunnels`, unlimited when unset.
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Visitors relayed at the same time, across all tunnels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// Idle HolePunch workers, across all sessions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_workers: Option<usize>,
+    /// Bytes relayed per period, in both directions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    /// Visitors accepted per period.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connections: Option<u64>,
+    #[serde(default)]
+    pub period: QuotaPeriod,
+}
+
+impl Quota {
+    fn is_unlimited(&self) -> bool {
+        *self == Quota::default()
+    }
+}
+
+/// How often the byte and connection quotas are reset, in UTC.
+#[derive(Deserialize, Serialize, ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    #[default]
+    Day,
+    Month,
+}
+
+impl QuotaPeriod {
+    /// Unix timestamp at which the current period started.
+    pub fn start(self) -> u64 {
+        match self {
+            QuotaPeriod::Day => today(),
+            QuotaPeriod::Month => month_start(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Key {
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+    /// Unix timestamp after which the key is rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
 }
 
-pub fn load_config() -> Result<Configuration> {
-    let file = read_to_string(CONFIG_PATH)?;
-    let config: Configuration = from_str(&file)?;
+#[derive(Deserialize, Serialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Create and delete tunnels
+    Tunnels,
+    /// Read-only access to status endpoints
+    Read,
+    /// Connect HolePunch workers
+    Workers,
+    /// View the status dashboard
+    Admin,
+}
+
+impl Scope {
+    /// Scopes granted to keys unless others are asked for. Admin access
+    /// always has to be given explicitly.
+    pub fn defaults() -> Vec<Scope> {
+        vec![Scope::Tunnels, Scope::Read, Scope::Workers]
+    }
+}
+
+impl Key {
+    pub fn new(hash: String, scopes: Vec<Scope>, expires_at: Option<u64>) -> Self {
+        Self {
+            hash,
+            scopes,
+            expires_at,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= now())
+    }
+}
+
+pub fn load_config(path: &Path) -> Result<Configuration> {
+    let file = read_to_string(path)
+        .map_err(|e| anyhow!("unable to read config {}: {e}", path.display()))?;
+    let mut config: Configuration = from_str(&file)?;
+    config.path = path.to_path_buf();
+
+    if migrate_legacy_keys(&mut config) {
+        warn!("legacy keys found in config, moving them to hashed named keys...");
+        write_config(&config)?;
+    }
+
     Ok(config)
 }
 
+/// Writes the configuration next to the original and renames it into place,
+/// so a crash can never leave a half-written file behind. The previous
+/// version is kept as `<path>.bak`.
 pub fn write_config(config: &Configuration) -> Result<()> {
     let file = toml::to_string(&config)?;
-    std::fs::write(CONFIG_PATH, file)?;
+
+    if config.path.exists() {
+        fs::copy(&config.path, with_suffix(&config.path, "bak"))?;
+    }
+
+    replace_file(&config.path, file.as_bytes())
+}
+
+/// Replaces a file atomically through a temporary file next to it, which
+/// keeps the permissions of the file it replaces. The directory is synced
+/// afterwards so the rename survives a crash.
+pub fn replace_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = with_suffix(path, "tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(contents)?;
+
+    match fs::metadata(path) {
+        Ok(metadata) => tmp.set_permissions(metadata.permissions())?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    tmp.sync_all()?;
+
+    fs::rename(&tmp_path, path)?;
+
+    // Directories can't be opened as files on Windows.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+
+        File::open(dir)?.sync_all()?;
+    }
+
     Ok(())
 }
+
+/// Appends `.<suffix>` to a path, e.g. `config.toml.bak`.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    path.into()
+}
+
+fn default_session_lease() -> u64 {
+    30
+}
+
+fn default_shutdown_grace() -> u64 {
+    30
+}
+
+fn default_auth_ban_max_failures() -> u32 {
+    10
+}
+
+fn default_auth_ban_window() -> u64 {
+    10 * 60
+}
+
+fn default_auth_ban_duration() -> u64 {
+    15 * 60
+}
+
+fn default_audit_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn is_default_audit_max_size(size: &u64) -> bool {
+    *size == default_audit_max_size()
+}
+
+fn default_access_log_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_access_log_keep() -> usize {
+    5
+}
+
+fn migrate_legacy_keys(config: &mut Configuration) -> bool {
+    let mut migrated = false;
+
+    for secret in config.secrets.values_mut() {
+        let hash = match (secret.key.take(), secret.key_hash.take()) {
+            (Some(key), _) => hash_key(&key),
+            (None, Some(hash)) => hash,
+            (None, None) => continue,
+        };
+
+        secret.keys.insert(
+            DEFAULT_KEY_NAME.to_string(),
+            Key::new(hash, Scope::defaults(), None),
+        );
+        migrated = true;
+    }
+
+    migrated
+}
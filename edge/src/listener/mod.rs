@@ -1,16 +1,21 @@
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsFd, OwnedFd};
 use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Result;
-use log::info;
+use log::{error, info};
 use tokio::sync::{
     mpsc::UnboundedReceiver,
     oneshot::{self, Sender},
-    Mutex,
+    watch, Mutex,
 };
 
-use crate::{listener::proxy::start_proxy, state::State};
+use crate::{
+    listener::proxy::{bind_tunnel_listener, start_proxy},
+    state::{State, TunnelStats},
+};
 
-use self::proxy::{Mode, Protocol};
+use self::proxy::{TunnelBind, TunnelSpec};
 
 pub mod proxy;
 pub mod worker;
@@ -18,51 +23,124 @@ pub mod worker;
 pub enum ListenerMessage {
     Listen {
         reply: Sender<Option<u16>>,
-        tunnel: String,
-        protocol: Protocol,
-        mode: Mode,
+        spec: TunnelSpec,
         name: String,
-        secret: String,
+        user: String,
+        bind: TunnelBind,
+        stats: Arc<TunnelStats>,
+    },
+    Update {
+        port: u16,
+        spec: TunnelSpec,
     },
     Stop {
         port: u16,
     },
+    /// Duplicates every listening socket, keyed by port, so they can be
+    /// handed over to a new edge process.
+    #[cfg(target_os = "linux")]
+    Export {
+        reply: Sender<HashMap<u16, OwnedFd>>,
+    },
+}
+
+struct ActiveListener {
+    closer: Sender<()>,
+    spec_tx: watch::Sender<TunnelSpec>,
+    #[cfg(target_os = "linux")]
+    socket: OwnedFd,
 }
 
 pub async fn start_listener(
     mut rx: UnboundedReceiver<ListenerMessage>,
     state: Arc<Mutex<State>>,
 ) -> Result<()> {
-    let mut port_closer_map = HashMap::new();
+    let mut listeners = HashMap::new();
     loop {
         match rx.recv().await {
             Some(ListenerMessage::Listen {
                 reply,
-                tunnel,
-                protocol,
-                mode,
+                spec,
                 name,
-                secret,
+                user,
+                bind,
+                stats,
             }) => {
-                info!("creating listener for tunnel {name} (to={tunnel}, proto={protocol:?}, mode={mode:?})");
+                info!(
+                    "creating listener for tunnel {name} (to={}, proto={:?}, mode={:?})",
+                    spec.target, spec.protocol, spec.mode
+                );
+
+                let listener = match bind_tunnel_listener(bind).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        error!("failed to bind to port: {e}");
+                        let _ = reply.send(None);
+                        continue;
+                    }
+                };
+
+                #[cfg(target_os = "linux")]
+                let socket = match listener.as_fd().try_clone_to_owned() {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        error!("failed to duplicate listener: {e}");
+                        let _ = reply.send(None);
+                        continue;
+                    }
+                };
 
                 let (tx, rx) = oneshot::channel();
+                let (spec_tx, spec_rx) = watch::channel(spec);
 
                 let result =
-                    start_proxy(tunnel, rx, protocol, mode, name, secret, state.clone()).await;
+                    start_proxy(listener, spec_rx, rx, name, user, stats, state.clone()).await;
                 reply.send(result).unwrap();
 
                 if let Some(port) = result {
-                    port_closer_map.insert(port, tx);
+                    listeners.insert(
+                        port,
+                        ActiveListener {
+                            closer: tx,
+                            spec_tx,
+                            #[cfg(target_os = "linux")]
+                            socket,
+                        },
+                    );
+                }
+            }
+            Some(ListenerMessage::Update { port, spec }) => {
+                info!(
+                    "updating listener for port {port} (to={}, proto={:?}, mode={:?})",
+                    spec.target, spec.protocol, spec.mode
+                );
+
+                if let Some(listener) = listeners.get(&port) {
+                    listener.spec_tx.send_replace(spec);
                 }
             }
             Some(ListenerMessage::Stop { port }) => {
                 info!("stopping listener for port {port}");
 
-                if let Some(closer) = port_closer_map.remove(&port) {
-                    closer.send(()).unwrap();
+                if let Some(listener) = listeners.remove(&port) {
+                    let _ = listener.closer.send(());
                 }
             }
+            #[cfg(target_os = "linux")]
+            Some(ListenerMessage::Export { reply }) => {
+                let mut sockets = HashMap::new();
+
+                for (port, listener) in &listeners {
+                    match listener.socket.try_clone() {
+                        Ok(socket) => {
+                            sockets.insert(*port, socket);
+                        }
+                        Err(e) => error!("failed to duplicate listener for port {port}: {e}"),
+                    }
+                }
+
+                let _ = reply.send(sockets);
+            }
             None => {}
         }
     }
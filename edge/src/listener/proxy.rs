@@ -1,65 +1,304 @@
 use byteorder::{BigEndian, ByteOrder};
-use rand::random;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashSet,
+    io,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, bail, Result};
-use log::{error, info};
+use log::{error, info, warn};
 use tokio::{
-    io::{copy, AsyncWriteExt},
+    io::{copy, split, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    join,
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpListener, TcpStream,
     },
     select,
-    sync::{oneshot::Receiver, Mutex},
+    sync::{oneshot::Receiver, watch, Mutex},
+    time::interval,
+};
+
+use crate::{
+    access_log::{self, AccessEntry},
+    auth::now,
+    state::{State, TunnelStats},
 };
 
-use crate::state::State;
+/// How often the traffic of an open connection is added to its user's
+/// pending traffic, which the edge writes to the storage every minute.
+const TRAFFIC_RECORD_INTERVAL: Duration = Duration::from_secs(60);
 
-#[derive(Deserialize, Debug, Clone)]
+/// How often a random port is drawn before giving up on finding one that
+/// isn't reserved.
+const RANDOM_BIND_ATTEMPTS: usize = 16;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub enum Protocol {
     Tcp,
     HAProxyV1,
     HAProxyV2,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub enum Mode {
     Reverse,
     HolePunch,
 }
 
+/// Where and how a tunnel relays its visitors.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TunnelSpec {
+    pub target: String,
+    pub protocol: Protocol,
+    pub mode: Mode,
+    /// Maximum number of concurrent visitors, unlimited if unset.
+    pub max_connections: Option<usize>,
+}
+
+/// Visitors being relayed by the edge as a whole. Unlike the tunnel's stats,
+/// it still counts those whose tunnel was removed while they were connected.
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of visitor connections still being relayed.
+pub fn active_connections() -> usize {
+    ACTIVE_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// Counts a visitor as active, for the edge, its tunnel and its user, for as
+/// long as it is alive.
+struct ConnectionGuard(Arc<TunnelStats>);
+
+impl ConnectionGuard {
+    fn new(stats: Arc<TunnelStats>) -> Self {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        stats.active_connections.fetch_add(1, Ordering::Relaxed);
+        stats.total_connections.fetch_add(1, Ordering::Relaxed);
+        stats
+            .user
+            .active_connections
+            .fetch_add(1, Ordering::Relaxed);
+        stats.user.connections.fetch_add(1, Ordering::Relaxed);
+        Self(stats)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+        self.0
+            .user
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Bytes relayed for a single visitor. They are added to the tunnel's and
+/// the user's totals as they flow, so its throughput is visible and counts
+/// towards the quota while connected.
+struct Traffic {
+    tunnel: Arc<TunnelStats>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Bytes not yet added to the user's pending traffic.
+    unrecorded_in: AtomicU64,
+    unrecorded_out: AtomicU64,
+    recorded: AtomicBool,
+}
+
+impl Traffic {
+    fn new(tunnel: Arc<TunnelStats>) -> Self {
+        Self {
+            tunnel,
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            unrecorded_in: AtomicU64::new(0),
+            unrecorded_out: AtomicU64::new(0),
+            recorded: AtomicBool::new(false),
+        }
+    }
+
+    fn add_in(&self, bytes: u64) {
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+        self.unrecorded_in.fetch_add(bytes, Ordering::Relaxed);
+        self.tunnel.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+        self.tunnel.user.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn add_out(&self, bytes: u64) {
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+        self.unrecorded_out.fetch_add(bytes, Ordering::Relaxed);
+        self.tunnel.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+        self.tunnel.user.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Adds the bytes relayed since the last call to the user's pending
+    /// traffic, and the connection itself the first time.
+    async fn record(&self, user: &str, state: &Mutex<State>) {
+        let bytes_in = self.unrecorded_in.swap(0, Ordering::Relaxed);
+        let bytes_out = self.unrecorded_out.swap(0, Ordering::Relaxed);
+        let connections = u64::from(!self.recorded.swap(true, Ordering::Relaxed));
+
+        if bytes_in == 0 && bytes_out == 0 && connections == 0 {
+            return;
+        }
+
+        state
+            .lock()
+            .await
+            .record_traffic(user, bytes_in, bytes_out, connections);
+    }
+}
+
+/// Reports every byte read through it, as the data flows.
+struct CountingReader<'a, R> {
+    inner: R,
+    count: &'a (dyn Fn(u64) + Sync),
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+
+        let read = buf.filled().len() - before;
+        (this.count)(read as u64);
+
+        result
+    }
+}
+
+/// Where a tunnel's listening socket comes from.
+pub enum TunnelBind {
+    /// Binds the requested port if it is free, or any port otherwise, short
+    /// of those reserved for offline tunnels.
+    Port {
+        port: Option<u16>,
+        reserved: HashSet<u16>,
+    },
+    /// Takes over a socket that is already listening, passed on by the edge
+    /// being upgraded.
+    #[cfg(target_os = "linux")]
+    Inherited(std::net::TcpListener),
+}
+
 pub async fn start_proxy(
-    target: String,
+    listener: TcpListener,
+    spec: watch::Receiver<TunnelSpec>,
     closer: Receiver<()>,
-    protocol: Protocol,
-    mode: Mode,
     name: String,
-    secret: String,
+    user: String,
+    stats: Arc<TunnelStats>,
     state: Arc<Mutex<State>>,
 ) -> Option<u16> {
-    info!("creating proxy for tunnel {name} (to={target}, proto={protocol:?}, mode={mode:?})");
+    {
+        let spec = spec.borrow();
+        info!(
+            "creating proxy for tunnel {name} (to={}, proto={:?}, mode={:?})",
+            spec.target, spec.protocol, spec.mode
+        );
+    }
 
-    match TcpListener::bind("0.0.0.0:0").await {
-        Ok(listener) => {
-            let port = listener.local_addr().unwrap().port();
+    match listener.local_addr() {
+        Ok(addr) => {
+            let port = addr.port();
 
             tokio::spawn(async move {
                 select! {
                     _ = async {
                         loop {
                             match listener.accept().await {
-                                Ok((socket, _)) => {
-                                    match handle_tcp_stream(socket, target.clone(), protocol.clone(), mode.clone(), secret.clone(), state.clone()).await {
-                                        Ok(_) => {}
-                                        Err(e) => {
-                                            error!("failed to handle connection: {e}");
-                                        }
+                                Ok((socket, addr)) => {
+                                    // Settings may change at any time, each visitor uses the latest ones.
+                                    let spec = spec.borrow().clone();
+
+                                    let refused = if spec.max_connections.is_some_and(|max| stats.active_connections.load(Ordering::Relaxed) >= max) {
+                                        Some("connection limit reached")
+                                    } else {
+                                        stats.user.check()
+                                    };
+
+                                    if let Some(reason) = refused {
+                                        warn!("tunnel {name} refused visitor {addr}: {reason}");
+
+                                        access_log::record(&AccessEntry {
+                                            timestamp: now(),
+                                            tunnel: &name,
+                                            user: &user,
+                                            visitor: addr,
+                                            mode: &spec.mode,
+                                            duration_ms: 0,
+                                            bytes_in: 0,
+                                            bytes_out: 0,
+                                            close_reason: reason,
+                                        });
+                                        continue;
                                     }
+
+                                    let guard = ConnectionGuard::new(stats.clone());
+                                    let name = name.clone();
+                                    let user = user.clone();
+                                    let state = state.clone();
+                                    let traffic = Traffic::new(stats.clone());
+
+                                    tokio::spawn(async move {
+                                        let _guard = guard;
+                                        let started = Instant::now();
+                                        let mode = spec.mode.clone();
+
+                                        // Long-lived connections are recorded as they go, so a
+                                        // restart doesn't lose what they relayed.
+                                        let handled = handle_tcp_stream(socket, port, spec, &user, state.clone(), &traffic);
+                                        tokio::pin!(handled);
+                                        let mut record = interval(TRAFFIC_RECORD_INTERVAL);
+
+                                        let result = loop {
+                                            select! {
+                                                result = &mut handled => break result,
+                                                _ = record.tick() => traffic.record(&user, &state).await,
+                                            }
+                                        };
+
+                                        let close_reason = match result {
+                                            Ok(reason) => reason,
+                                            Err(e) => {
+                                                error!("failed to handle connection: {e}");
+                                                e.to_string()
+                                            }
+                                        };
+
+                                        let bytes_in = traffic.bytes_in.load(Ordering::Relaxed);
+                                        let bytes_out = traffic.bytes_out.load(Ordering::Relaxed);
+
+                                        access_log::record(&AccessEntry {
+                                            timestamp: now(),
+                                            tunnel: &name,
+                                            user: &user,
+                                            visitor: addr,
+                                            mode: &mode,
+                                            duration_ms: started.elapsed().as_millis() as u64,
+                                            bytes_in,
+                                            bytes_out,
+                                            close_reason: &close_reason,
+                                        });
+
+                                        traffic.record(&user, &state).await;
+                                    });
                                 }
                                 Err(e) => {
                                     error!("failed to accept connection: {e}");
@@ -75,41 +314,88 @@ pub async fn start_proxy(
             Some(port)
         }
         Err(e) => {
-            error!("failed to bind to port: {e}");
+            error!("failed to read listener address: {e}");
             None
         }
     }
 }
 
+/// Binds the tunnel's listening socket. Privileged ports are never handed out.
+pub async fn bind_tunnel_listener(bind: TunnelBind) -> io::Result<TcpListener> {
+    let (port, reserved) = match bind {
+        TunnelBind::Port { port, reserved } => (port, reserved),
+        #[cfg(target_os = "linux")]
+        TunnelBind::Inherited(listener) => {
+            listener.set_nonblocking(true)?;
+            return TcpListener::from_std(listener);
+        }
+    };
+
+    if let Some(port) = port.filter(|port| *port >= 1024) {
+        match TcpListener::bind(("0.0.0.0", port)).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) => warn!("unable to reuse port {port}: {e}"),
+        }
+    }
+
+    // Ports the kernel picks may be reserved. Those are kept bound until a
+    // free one comes up so that the same port isn't handed out again.
+    let mut taken = Vec::new();
+
+    for _ in 0..RANDOM_BIND_ATTEMPTS {
+        let listener = TcpListener::bind("0.0.0.0:0").await?;
+
+        if !reserved.contains(&listener.local_addr()?.port()) {
+            return Ok(listener);
+        }
+
+        taken.push(listener);
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "no unreserved port available",
+    ))
+}
+
 async fn handle_tcp_stream(
     stream: TcpStream,
-    target: String,
-    protocol: Protocol,
-    mode: Mode,
-    secret: String,
+    port: u16,
+    spec: TunnelSpec,
+    user: &str,
     state: Arc<Mutex<State>>,
-) -> Result<()> {
-    match mode {
+    traffic: &Traffic,
+) -> Result<String> {
+    match spec.mode {
         Mode::Reverse => {
-            let target_stream = TcpStream::connect(target.clone()).await?;
-            merge_coupled_streams(stream, target_stream, protocol, mode, target).await
+            let target_stream = TcpStream::connect(spec.target.clone()).await?;
+            merge_coupled_streams(stream, target_stream, spec, traffic).await
         }
 
         Mode::HolePunch => {
-            let mut state = state.lock().await;
+            let worker = {
+                let mut state = state.lock().await;
 
-            let secret = state
-                .secrets
-                .get_mut(secret.as_str())
-                .ok_or_else(|| anyhow!("no client found for secret \"{secret}\""))?;
+                let secret = state
+                    .secrets
+                    .get_mut(user)
+                    .ok_or_else(|| anyhow!("no client found for user \"{user}\""))?;
 
-            let worker = {
-                let index = random::<usize>() % secret.workers.len();
-                secret.workers.remove(index)
+                secret
+                    .take_worker(port)
+                    .ok_or_else(|| anyhow!("no idle worker for tunnel on port {port}"))?
             };
 
-            worker.handoff_tx.send(target.clone()).unwrap();
-            let (server_read, server_write) = worker.stream_rx.await.unwrap();
+            worker
+                .handoff_tx
+                .send(spec.target.clone())
+                .map_err(|_| anyhow!("worker {} went away", worker.client_addr))?;
+            let (server_read, server_write) = split(
+                worker
+                    .stream_rx
+                    .await
+                    .map_err(|_| anyhow!("worker {} went away", worker.client_addr))?,
+            );
             let (client_read, client_write) = stream.into_split();
 
             merge_streams(
@@ -117,9 +403,9 @@ async fn handle_tcp_stream(
                 client_write,
                 server_read,
                 server_write,
-                protocol,
-                mode,
-                target,
+                None,
+                spec,
+                traffic,
             )
             .await
         }
@@ -132,10 +418,10 @@ async fn handle_tcp_stream(
 async fn merge_coupled_streams(
     client: TcpStream,
     server: TcpStream,
-    protocol: Protocol,
-    mode: Mode,
-    target: String,
-) -> Result<()> {
+    spec: TunnelSpec,
+    traffic: &Traffic,
+) -> Result<String> {
+    let server_addr = server.peer_addr()?;
     let (client_read, client_write) = client.into_split();
     let (server_read, server_write) = server.into_split();
 
@@ -144,26 +430,33 @@ async fn merge_coupled_streams(
         client_write,
         server_read,
         server_write,
-        protocol,
-        mode,
-        target,
+        Some(server_addr),
+        spec,
+        traffic,
     )
     .await
 }
 
+/// Relays a visitor to the target, which the edge either connected to
+/// itself, at `server_addr`, or reaches through a HolePunch worker.
 async fn merge_streams(
     mut client_read: OwnedReadHalf,
     mut client_write: OwnedWriteHalf,
-    mut server_read: OwnedReadHalf,
-    mut server_write: OwnedWriteHalf,
-    protocol: Protocol,
-    mode: Mode,
-    target: String,
-) -> Result<()> {
+    mut server_read: impl AsyncRead + Unpin,
+    mut server_write: impl AsyncWrite + Unpin,
+    server_addr: Option<SocketAddr>,
+    spec: TunnelSpec,
+    traffic: &Traffic,
+) -> Result<String> {
+    let TunnelSpec {
+        target, protocol, ..
+    } = spec;
+
     match protocol {
         Protocol::HAProxyV1 => {
             if let Err(e) =
-                send_haproxy_v1_header(&mut client_write, &mut server_write, mode, target).await
+                send_haproxy_v1_header(&mut client_write, &mut server_write, server_addr, target)
+                    .await
             {
                 error!("failed to send HAProxy v1 header: {e}");
             }
@@ -171,7 +464,8 @@ async fn merge_streams(
 
         Protocol::HAProxyV2 => {
             if let Err(e) =
-                send_haproxy_v2_header(&mut client_write, &mut server_write, mode, target).await
+                send_haproxy_v2_header(&mut client_write, &mut server_write, server_addr, target)
+                    .await
             {
                 error!("failed to send HAProxy v2 header: {e}");
             }
@@ -180,19 +474,45 @@ async fn merge_streams(
         _ => {}
     }
 
-    tokio::spawn(async move {
-        if let Err(e) = copy(&mut server_read, &mut client_write).await {
-            error!("failed to copy from target to stream: {e}");
-        }
-    });
+    // Whichever side stops first is why the connection closed.
+    let close_reason = OnceLock::new();
+    let close_reason = &close_reason;
+
+    // Each direction owns its halves, so finishing one shuts down its writer
+    // while the other keeps running until the connection is fully closed.
+    let downstream = async move {
+        let mut server_read = CountingReader {
+            inner: &mut server_read,
+            count: &|bytes| traffic.add_out(bytes),
+        };
+
+        match copy(&mut server_read, &mut client_write).await {
+            Ok(_) => close_reason.get_or_init(|| "target closed".to_string()),
+            Err(e) => {
+                error!("failed to copy from target to stream: {e}");
+                close_reason.get_or_init(|| format!("target error: {e}"))
+            }
+        };
+    };
 
-    tokio::spawn(async move {
-        if let Err(e) = copy(&mut client_read, &mut server_write).await {
-            error!("failed to copy from stream to target: {e}");
-        }
-    });
+    let upstream = async move {
+        let mut client_read = CountingReader {
+            inner: &mut client_read,
+            count: &|bytes| traffic.add_in(bytes),
+        };
+
+        match copy(&mut client_read, &mut server_write).await {
+            Ok(_) => close_reason.get_or_init(|| "visitor closed".to_string()),
+            Err(e) => {
+                error!("failed to copy from stream to target: {e}");
+                close_reason.get_or_init(|| format!("visitor error: {e}"))
+            }
+        };
+    };
 
-    Ok(())
+    join!(downstream, upstream);
+
+    Ok(close_reason.get().cloned().unwrap_or_default())
 }
 
 /// The v1 header is a human-readable, text-based format.
@@ -202,13 +522,13 @@ async fn merge_streams(
 /// PROXY TCP4 192.168.0.1 192.168.0.2 12345 80\r\n
 async fn send_haproxy_v1_header(
     client_write: &mut OwnedWriteHalf,
-    server_write: &mut OwnedWriteHalf,
-    mode: Mode,
+    server_write: &mut (impl AsyncWrite + Unpin),
+    server_addr: Option<SocketAddr>,
     target: String,
 ) -> Result<()> {
-    let dst_addr = match mode {
-        Mode::Reverse => server_write.peer_addr()?,
-        Mode::HolePunch => {
+    let dst_addr = match server_addr {
+        Some(server_addr) => server_addr,
+        None => {
             let target = target.parse::<SocketAddr>()?;
             SocketAddr::new(target.ip(), target.port())
         }
@@ -250,13 +570,13 @@ async fn send_haproxy_v1_header(
 /// 0D0A0D0A  21 11 00 0C  C0A80001  C0A80002  3039 0050
 async fn send_haproxy_v2_header(
     client_write: &mut OwnedWriteHalf,
-    server_write: &mut OwnedWriteHalf,
-    mode: Mode,
+    server_write: &mut (impl AsyncWrite + Unpin),
+    server_addr: Option<SocketAddr>,
     target: String,
 ) -> Result<()> {
-    let dst_addr = match mode {
-        Mode::Reverse => server_write.peer_addr()?,
-        Mode::HolePunch => {
+    let dst_addr = match server_addr {
+        Some(server_addr) => server_addr,
+        None => {
             let target = target.parse::<SocketAddr>()?;
             SocketAddr::new(target.ip(), target.port())
         }
@@ -1,88 +1,200 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, bail, Result};
-use log::{error, info};
+use log::{debug, error, info};
+use openssl::ssl::SslAcceptor;
+use serde_json::json;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream},
     select,
-    sync::{oneshot::channel, Mutex},
+    sync::{
+        oneshot::{channel, Receiver, Sender},
+        Mutex,
+    },
+    task::JoinHandle,
+    time::{sleep, timeout},
 };
 
-use crate::state::{State, Worker};
+use crate::{
+    audit::{self, AuditEvent},
+    auth::Credentials,
+    bans::AuthFailures,
+    config::{CertificateIdentity, Scope, TlsConfig},
+    state::{State, Worker},
+    tls::{self, ClientCertificate},
+};
+
+/// A worker connection, over TLS when the edge has a certificate.
+pub trait WorkerIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> WorkerIo for T {}
+
+pub type WorkerStream = Box<dyn WorkerIo>;
+
+/// How long a worker has to complete the TLS handshake and send its key and
+/// session.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest key or session line a worker may send.
+const MAX_LINE_LENGTH: u64 = 1024;
+
+struct WorkerTls {
+    acceptor: SslAcceptor,
+    identity: CertificateIdentity,
+}
 
-pub async fn start_worker_server(state: Arc<Mutex<State>>) -> Result<u16> {
+/// Accepts HolePunch workers on the given socket. The returned task can be
+/// aborted to stop accepting them.
+pub fn start_worker_server(
+    listener: std::net::TcpListener,
+    state: Arc<Mutex<State>>,
+    tls: Option<&TlsConfig>,
+) -> Result<JoinHandle<()>> {
     info!("starting worker server...");
 
-    match TcpListener::bind("0.0.0.0:0").await {
-        Ok(listener) => {
-            let port = listener.local_addr().unwrap().port();
-
-            tokio::spawn(async move {
-                loop {
-                    match listener.accept().await {
-                        Ok((socket, _)) => {
-                            match handle_worker_tcp_stream(socket, state.clone()).await {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    error!("failed to handle worker connection: {e}");
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!("failed to accept worker connection: {e}");
-                        }
-                    }
+    let tls = match tls {
+        Some(tls) => Some(Arc::new(WorkerTls {
+            acceptor: tls::acceptor(tls)?.build(),
+            identity: tls.identity,
+        })),
+        None => None,
+    };
+
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+
+    Ok(tokio::spawn(async move {
+        let auth_failures = state.lock().await.auth_failures.clone();
+
+        loop {
+            match listener.accept().await {
+                Ok((_, addr)) if auth_failures.banned_for(addr.ip()).is_some() => {
+                    debug!("refusing worker from banned {addr}");
                 }
-            });
+                Ok((socket, addr)) => {
+                    let state = state.clone();
+                    let tls = tls.clone();
+                    let auth_failures = auth_failures.clone();
 
-            Ok(port)
-        }
-        Err(e) => {
-            bail!("failed to bind to port: {e}")
+                    // Answered separately, failed authentications are delayed.
+                    tokio::spawn(async move {
+                        if let Err(e) = accept_worker(socket, addr, tls, state, auth_failures).await
+                        {
+                            error!("failed to handle worker connection: {e}");
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("failed to accept worker connection: {e}");
+                }
+            }
         }
-    }
+    }))
 }
 
-async fn handle_worker_tcp_stream(stream: TcpStream, state: Arc<Mutex<State>>) -> Result<()> {
-    let client_addr = stream.peer_addr().unwrap().to_string();
-    let (read, mut write) = stream.into_split();
+async fn accept_worker(
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    tls: Option<Arc<WorkerTls>>,
+    state: Arc<Mutex<State>>,
+    auth_failures: Arc<AuthFailures>,
+) -> Result<()> {
+    let Some(tls) = tls else {
+        return handle_worker_stream(stream, peer_addr, None, state, auth_failures).await;
+    };
 
-    let mut reader = BufReader::new(read);
+    let stream = timeout(HANDSHAKE_TIMEOUT, tls::accept(&tls.acceptor, stream))
+        .await
+        .map_err(|_| anyhow!("TLS handshake with {peer_addr} timed out"))?
+        .map_err(|e| anyhow!("TLS handshake with {peer_addr} failed: {e}"))?;
+    let certificate = tls::client_certificate(stream.ssl(), tls.identity);
 
-    let mut secret = String::new();
-    reader.read_line(&mut secret).await?;
+    handle_worker_stream(stream, peer_addr, certificate, state, auth_failures).await
+}
 
-    secret.pop();
-    let secret = secret.trim().to_string();
+/// Reads the worker's secret key and session, one per line. Workers with a
+/// client certificate send an empty line instead of a key.
+async fn handle_worker_stream(
+    stream: impl WorkerIo + 'static,
+    peer_addr: SocketAddr,
+    certificate: Option<ClientCertificate>,
+    state: Arc<Mutex<State>>,
+    auth_failures: Arc<AuthFailures>,
+) -> Result<()> {
+    let client_addr = peer_addr.to_string();
+    let source = peer_addr.ip().to_string();
 
-    let handoff_rx;
-    let socket_tx;
-    let close_rx;
-    {
-        let mut state = state.lock().await;
+    let mut reader = BufReader::new(stream);
 
-        let secret = state
-            .secrets
-            .get_mut(&secret)
-            .ok_or(anyhow!("invalid secret: \"{secret}\""))?;
+    let lines = timeout(HANDSHAKE_TIMEOUT, async {
+        let secret = read_worker_line(&mut reader).await?;
+        let session = read_worker_line(&mut reader).await?;
+        Ok((secret, session))
+    })
+    .await
+    .unwrap_or_else(|_| Err(anyhow!("no key and session received in time")));
 
-        let (tx, rx) = channel();
-        let (c_tx, c_rx) = channel();
-        let (s_tx, s_rx) = channel();
+    // Nothing was authenticated, so this counts as a failed attempt.
+    let (secret, session) = match lines {
+        Ok(lines) => lines,
+        Err(e) => {
+            auth_failures.record_failure(peer_addr.ip());
+            bail!("worker {peer_addr} failed to authenticate: {e}");
+        }
+    };
 
-        let worker = Worker {
+    let credentials = match certificate {
+        Some(certificate) if secret.is_empty() => Credentials::Certificate(certificate.0),
+        _ => Credentials::Key(secret),
+    };
+
+    let registration = {
+        let (identity, result) = register_worker(
+            &mut *state.lock().await,
+            &credentials,
+            &session,
             client_addr,
-            stream_rx: s_rx,
-            handoff_tx: tx,
-            close_tx: c_tx,
+        );
+
+        let outcome = match &result {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
         };
-        secret.workers.push(worker);
 
-        handoff_rx = rx;
-        socket_tx = s_tx;
-        close_rx = c_rx;
-    }
+        audit::record(AuditEvent {
+            user: identity.as_ref().map(|identity| identity.user.as_str()),
+            key: identity.as_ref().map(|identity| identity.key.as_str()),
+            source: &source,
+            action: if identity
+                .as_ref()
+                .is_some_and(|identity| identity.authorized)
+            {
+                "worker.register"
+            } else {
+                "auth.failed"
+            },
+            params: json!({"endpoint": "worker", "session": session}),
+            outcome: &outcome,
+        });
+
+        if identity.is_none() {
+            let delay = auth_failures.record_failure(peer_addr.ip());
+
+            // The connection stays open until the delay is over.
+            sleep(delay).await;
+        } else {
+            auth_failures.record_success(peer_addr.ip());
+        }
+
+        result?
+    };
+
+    let Registration {
+        handoff_rx,
+        socket_tx,
+        close_rx,
+    } = registration;
 
     tokio::spawn(async move {
         select! {
@@ -101,9 +213,11 @@ async fn handle_worker_tcp_stream(stream: TcpStream, state: Arc<Mutex<State>>) -
                         buf.insert(0, buf.len() as u8);
                         buf
                     };
-                    write.write_all(&packet_body).await.unwrap();
+                    reader.write_all(&packet_body).await.unwrap();
+                    reader.flush().await.unwrap();
 
-                    socket_tx.send((reader.into_inner(), write)).unwrap();
+                    // Dropped along with the worker if the visitor is gone.
+                    let _ = socket_tx.send(Box::new(reader));
                 }
             }
         }
@@ -111,3 +225,121 @@ async fn handle_worker_tcp_stream(stream: TcpStream, state: Arc<Mutex<State>>) -
 
     Ok(())
 }
+
+/// Reads a line of the worker's key and session, without its line break.
+async fn read_worker_line(reader: &mut BufReader<impl WorkerIo>) -> Result<String> {
+    let mut line = String::new();
+    (&mut *reader)
+        .take(MAX_LINE_LENGTH + 1)
+        .read_line(&mut line)
+        .await?;
+
+    if line.len() as u64 > MAX_LINE_LENGTH {
+        bail!("line longer than {MAX_LINE_LENGTH} bytes");
+    }
+
+    Ok(line.trim().to_string())
+}
+
+/// The channels connecting a registered worker to the visitor it is handed.
+struct Registration {
+    handoff_rx: Receiver<String>,
+    socket_tx: Sender<WorkerStream>,
+    close_rx: Receiver<()>,
+}
+
+/// The user and key a worker's credentials belong to.
+struct WorkerIdentity {
+    user: String,
+    key: String,
+    /// Whether the key allows registering workers.
+    authorized: bool,
+}
+
+/// Adds the worker to its session's idle workers. The user and key it
+/// authenticated as are returned whether or not it was registered.
+fn register_worker(
+    state: &mut State,
+    credentials: &Credentials,
+    session: &str,
+    client_addr: String,
+) -> (Option<WorkerIdentity>, Result<Registration>) {
+    let Some((user, key)) = state.identify(credentials) else {
+        return (None, Err(anyhow!("invalid secret from {client_addr}")));
+    };
+
+    let authorized = state
+        .authorize(&user, &key, credentials, Some(Scope::Workers))
+        .is_some();
+    let result = add_worker(state, &user, authorized, session, client_addr);
+
+    let identity = WorkerIdentity {
+        user,
+        key,
+        authorized,
+    };
+
+    (Some(identity), result)
+}
+
+fn add_worker(
+    state: &mut State,
+    user: &str,
+    authorized: bool,
+    session: &str,
+    client_addr: String,
+) -> Result<Registration> {
+    if state.draining {
+        bail!("refusing worker from {client_addr}, shutting down");
+    }
+
+    let secret = state
+        .secrets
+        .get_mut(user)
+        .filter(|_| authorized)
+        .ok_or(anyhow!("invalid secret from {client_addr}"))?;
+
+    let idle_workers = secret
+        .sessions
+        .values()
+        .map(|session| session.workers.len())
+        .sum::<usize>();
+
+    if secret
+        .usage
+        .quota
+        .read()
+        .unwrap()
+        .max_workers
+        .is_some_and(|max| idle_workers >= max)
+    {
+        bail!(
+            "refusing worker from {client_addr}, user {} is at its worker limit",
+            secret.name
+        );
+    }
+
+    let session = secret
+        .sessions
+        .get_mut(session)
+        .filter(|session| !session.closed)
+        .ok_or(anyhow!("unknown session from {client_addr}"))?;
+
+    let (tx, rx) = channel();
+    let (c_tx, c_rx) = channel();
+    let (s_tx, s_rx) = channel();
+
+    let worker = Worker {
+        client_addr,
+        stream_rx: s_rx,
+        handoff_tx: tx,
+        close_tx: c_tx,
+    };
+    session.workers.push(worker);
+
+    Ok(Registration {
+        handoff_rx: rx,
+        socket_tx: s_tx,
+        close_rx: c_rx,
+    })
+}
@@ -1,38 +1,82 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use audit::init_audit_log;
 use clap::Parser;
-use cli::{add_user::add_user, delete_user::delete_user, serve::serve, Commands};
+use cli::{
+    check_config::check_config, run_storage_command, serve::serve, verify_audit::verify_audit,
+    Commands,
+};
 use config::{load_config, Configuration};
+use logging::init_logging;
+use storage::open_storage;
 
 use crate::cli::Cli;
 
+pub mod access_log;
 pub mod api;
+pub mod audit;
+pub mod auth;
+pub mod bans;
 pub mod cli;
 pub mod config;
 pub mod listener;
 pub mod state;
+pub mod storage;
+pub mod tls;
+#[cfg(target_os = "linux")]
+pub mod upgrade;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    init_logging();
-
-    let cfg = load_config()?;
-    let cfg: &'static mut Configuration = Box::leak(Box::new(cfg));
-
     let cli = Cli::parse();
 
+    init_logging(cli.log_format);
+
     match cli.command {
-        Commands::Serve {} => serve(cfg).await,
-        Commands::AddUser { name, max_tunnels } => add_user(cfg, name, max_tunnels),
-        Commands::DeleteUser { name_or_key } => delete_user(cfg, name_or_key),
+        // Checked before loading, which would fail on the first problem or
+        // migrate the file.
+        Commands::CheckConfig {} => check_config(&cli.config),
+        Commands::VerifyAudit {} => verify_audit(&cli.config),
+        Commands::Serve { overrides } => {
+            let cfg = load_config(&cli.config)?;
+            let cfg: &'static mut Configuration = Box::leak(Box::new(cfg));
+
+            overrides.apply(cfg);
+            serve(cfg).await
+        }
+        command => {
+            // A running edge applies the command live, and writes the storage itself.
+            #[cfg(unix)]
+            if let Some(output) =
+                cli::admin::send_command(&cli::admin::socket_path(&cli.config), &command).await?
+            {
+                if output.is_empty() {
+                    println!("applied to the running edge");
+                }
+
+                print_output(&output);
+                return Ok(());
+            }
+
+            if command.needs_server() {
+                bail!("no edge is running with {}", cli.config.display());
+            }
+
+            let cfg = load_config(&cli.config)?;
+            let mut storage = open_storage(&cfg)?;
+
+            if command.audit_action(storage.as_ref()).is_some() {
+                init_audit_log(&cfg)?;
+            }
+
+            print_output(&run_storage_command(storage.as_mut(), command)?);
+
+            Ok(())
+        }
     }
 }
 
-fn init_logging() {
-    if cfg!(debug_assertions) {
-        std::env::set_var("RUST_LOG", "debug");
-    } else if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info");
+fn print_output(output: &str) {
+    if !output.is_empty() {
+        println!("{}", output.trim_end());
     }
-
-    env_logger::init();
 }
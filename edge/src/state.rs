@@ -1,38 +1,565 @@
-use std::collections::HashMap;
-
-use tokio::{
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+use std::{
+    collections::{HashMap, HashSet},
     sync::{
-        mpsc::UnboundedSender,
-        oneshot::{Receiver, Sender},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
     },
+    time::Instant,
+};
+
+use log::{error, info, warn};
+use rand::random;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    mpsc::UnboundedSender,
+    oneshot::{Receiver, Sender},
+    Mutex,
 };
 
-use crate::{config::Configuration, listener::ListenerMessage};
+use crate::{
+    auth::{verify_key, Credentials, CERTIFICATE_KEY_NAME},
+    bans::AuthFailures,
+    config::{self, Configuration, Key, Quota, QuotaPeriod, Scope},
+    listener::{
+        proxy::{Mode, Protocol, TunnelSpec},
+        worker::WorkerStream,
+        ListenerMessage,
+    },
+    storage::{today, ReservationEntry, StorageTask, Traffic},
+};
 
 pub struct State {
     pub cfg: &'static Configuration,
     pub worker_port: Option<u16>,
     pub listener_tx: UnboundedSender<ListenerMessage>,
+    /// Users keyed by their name.
     pub secrets: HashMap<String, Secret>,
+    /// Set once the edge is shutting down. New sessions, tunnels and workers
+    /// are refused while in-flight connections finish.
+    pub draining: bool,
+    pub storage: StorageTask,
+    /// Traffic relayed per user and day, since it was last written to the
+    /// storage.
+    pub pending_traffic: HashMap<(String, u64), Traffic>,
+    /// Shared with the API middleware and the worker port, which check it
+    /// without locking the state.
+    pub auth_failures: Arc<AuthFailures>,
 }
 
 pub struct Secret {
-    pub secret: String,
+    pub name: String,
+    pub keys: HashMap<String, Key>,
     pub max_tunnels: usize,
+    /// Connected clients keyed by their session id.
+    pub sessions: HashMap<String, Session>,
     pub active_tunnels: Vec<Tunnel>,
+    /// Ports of the user's tunnels keyed by tunnel name, including those
+    /// whose client is offline. Kept in the storage across restarts.
+    pub reservations: HashMap<String, Reservation>,
+    pub usage: Arc<UserUsage>,
+    /// Scopes of the user's client certificates, if they may log in with one.
+    pub certificate_scopes: Option<Vec<Scope>>,
+}
+
+/// A user's usage, shared with the listeners of its tunnels so quotas are
+/// enforced without locking the state.
+#[derive(Default)]
+pub struct UserUsage {
+    pub quota: RwLock<Quota>,
+    pub active_connections: AtomicUsize,
+    /// Start of the quota period the counters below belong to.
+    pub period_start: AtomicU64,
+    /// Visitors accepted during the period, including those already
+    /// written to the storage.
+    pub connections: AtomicU64,
+    /// Bytes relayed during the period, in both directions.
+    pub bytes: AtomicU64,
+}
+
+/// A tunnel's port, held for its user until the tunnel is deleted.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Reservation {
+    pub port: u16,
+    pub protocol: Protocol,
+    pub mode: Mode,
+}
+
+pub struct Session {
+    pub client_addr: String,
+    pub lease_expires: Instant,
+    /// Idle HolePunch workers connected by this client.
     pub workers: Vec<Worker>,
+    /// Set once the client said goodbye. The session is kept until its
+    /// lease runs out so the client learns it should not reconnect.
+    pub closed: bool,
 }
 
 pub struct Tunnel {
     pub name: String,
-    pub target: String,
+    pub spec: TunnelSpec,
     pub port: u16,
+    pub session: String,
+    /// Unix timestamp of the tunnel's creation.
+    pub created_at: u64,
+    pub stats: Arc<TunnelStats>,
+}
+
+/// Live counters updated by the tunnel's listener.
+pub struct TunnelStats {
+    /// Usage of the tunnel's owner, which every visitor counts towards.
+    pub user: Arc<UserUsage>,
+    pub active_connections: AtomicUsize,
+    pub total_connections: AtomicU64,
+    /// Bytes sent by visitors to the target.
+    pub bytes_in: AtomicU64,
+    /// Bytes sent by the target back to visitors.
+    pub bytes_out: AtomicU64,
+}
+
+impl TunnelStats {
+    pub fn new(user: Arc<UserUsage>) -> Self {
+        Self {
+            user,
+            active_connections: AtomicUsize::new(0),
+            total_connections: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+        }
+    }
 }
 
 pub struct Worker {
     pub client_addr: String,
     pub handoff_tx: Sender<String>,
-    pub stream_rx: Receiver<(OwnedReadHalf, OwnedWriteHalf)>,
+    pub stream_rx: Receiver<WorkerStream>,
     pub close_tx: Sender<()>,
 }
+
+impl State {
+    /// Stops every tunnel listener and idle worker, so no new visitor is
+    /// accepted. Connections already relayed keep running.
+    pub fn start_draining(&mut self) {
+        self.draining = true;
+
+        for secret in self.secrets.values_mut() {
+            for tunnel in &secret.active_tunnels {
+                if let Err(e) = self
+                    .listener_tx
+                    .send(ListenerMessage::Stop { port: tunnel.port })
+                {
+                    error!("failed to send stop message: {e}");
+                }
+            }
+
+            for session in secret.sessions.values_mut() {
+                for worker in session.workers.drain(..) {
+                    if worker.close_tx.send(()).is_err() {
+                        error!("failed to send close message: {}", worker.client_addr);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Finds the user owning the given secret key or client certificate, as
+    /// long as the key has not expired and carries the required scope.
+    pub fn authenticate(
+        &mut self,
+        credentials: &Credentials,
+        scope: Option<Scope>,
+    ) -> Option<&mut Secret> {
+        let (user, key_name) = self.identify(credentials)?;
+        self.authorize(&user, &key_name, credentials, scope)
+    }
+
+    /// The user found by [`State::identify`], as long as the key it found
+    /// carries the required scope.
+    pub fn authorize(
+        &mut self,
+        user: &str,
+        key_name: &str,
+        credentials: &Credentials,
+        scope: Option<Scope>,
+    ) -> Option<&mut Secret> {
+        let secret = self.secrets.get_mut(user)?;
+
+        let allowed = match credentials {
+            Credentials::Key(_) => secret
+                .keys
+                .get(key_name)
+                .is_some_and(|k| scope.is_none_or(|s| k.scopes.contains(&s))),
+            Credentials::Certificate(_) => secret
+                .certificate_scopes
+                .as_ref()
+                .is_some_and(|scopes| scope.is_none_or(|s| scopes.contains(&s))),
+        };
+
+        allowed.then_some(secret)
+    }
+
+    /// Names of the user and key the given credentials belong to, whatever
+    /// the key's scopes.
+    pub fn identify(&self, credentials: &Credentials) -> Option<(String, String)> {
+        match credentials {
+            Credentials::Key(key) => self.secrets.values().find_map(|secret| {
+                secret
+                    .find_key(key)
+                    .map(|(name, _)| (secret.name.clone(), name.clone()))
+            }),
+            Credentials::Certificate(names) => names
+                .iter()
+                .find(|name| self.secrets.contains_key(*name))
+                .map(|name| (name.clone(), CERTIFICATE_KEY_NAME.to_string())),
+        }
+    }
+
+    /// Ports reserved by any tunnel, online or not.
+    pub fn reserved_ports(&self) -> HashSet<u16> {
+        self.secrets
+            .values()
+            .flat_map(|secret| secret.reservations.values())
+            .map(|reservation| reservation.port)
+            .collect()
+    }
+
+    /// Takes over the reservations of existing users read from the storage.
+    /// Their tunnels stay offline until the client creates them again.
+    pub fn restore_reservations(&mut self, entries: Vec<ReservationEntry>) {
+        let mut restored = 0;
+
+        for entry in entries {
+            let Some(secret) = self.secrets.get_mut(&entry.user) else {
+                warn!(
+                    "dropping reservation of tunnel {} of removed user {}",
+                    entry.name, entry.user
+                );
+                continue;
+            };
+
+            secret.reservations.insert(entry.name, entry.reservation);
+            restored += 1;
+        }
+
+        if restored > 0 {
+            info!("restored {restored} tunnel reservation(s)");
+        }
+    }
+
+    /// Queues the reservations to be written to the storage. Failures are
+    /// only logged, the tunnels keep working without it.
+    pub fn save_reservations(&mut self) {
+        let entries = self
+            .secrets
+            .values()
+            .flat_map(|secret| {
+                secret
+                    .reservations
+                    .iter()
+                    .map(|(name, reservation)| ReservationEntry {
+                        user: secret.name.clone(),
+                        name: name.clone(),
+                        reservation: reservation.clone(),
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        self.storage.queue(move |storage| {
+            if let Err(e) = storage.save_reservations(&entries) {
+                error!("failed to write tunnel reservations: {e}");
+            }
+        });
+    }
+
+    /// Counts traffic relayed for a visitor towards its user. A connection is
+    /// recorded in parts while it lasts, and counted with the first one.
+    pub fn record_traffic(&mut self, user: &str, bytes_in: u64, bytes_out: u64, connections: u64) {
+        self.pending_traffic
+            .entry((user.to_string(), today()))
+            .or_default()
+            .add(&Traffic {
+                bytes_in,
+                bytes_out,
+                connections,
+            });
+    }
+
+    /// Adds the pending traffic to the totals of the days it was relayed on
+    /// in the storage, without holding the state while it is written.
+    /// Traffic that fails to be written is kept for the next attempt.
+    pub async fn flush_traffic(state: &Mutex<State>) {
+        let written = {
+            let mut state = state.lock().await;
+            let pending = std::mem::take(&mut state.pending_traffic);
+
+            state.storage.run(move |storage| {
+                pending
+                    .into_iter()
+                    .filter(|((user, day), traffic)| {
+                        let result = storage.add_traffic(user, *day, traffic);
+
+                        if let Err(e) = &result {
+                            error!("failed to write traffic of user {user}: {e}");
+                        }
+
+                        result.is_err()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        match written.await {
+            Ok(failed) if failed.is_empty() => {}
+            Ok(failed) => {
+                let mut state = state.lock().await;
+
+                for (key, traffic) in failed {
+                    state.pending_traffic.entry(key).or_default().add(&traffic);
+                }
+            }
+            Err(e) => error!("failed to write traffic: {e}"),
+        }
+    }
+
+    /// Brings the users in line with the configuration. Users that remain
+    /// keep their sessions and tunnels, removed ones are disconnected and
+    /// lose their reservations.
+    pub fn sync_secrets(&mut self, secrets: &HashMap<String, config::Secret>) {
+        let listener_tx = self.listener_tx.clone();
+        let mut released = false;
+
+        self.secrets.retain(|name, secret| {
+            if secrets.contains_key(name) {
+                return true;
+            }
+
+            let sessions = secret.sessions.keys().cloned().collect::<Vec<_>>();
+            for session in sessions {
+                secret.end_session(&session, &listener_tx);
+            }
+
+            released |= !secret.reservations.is_empty();
+            false
+        });
+
+        if released {
+            self.save_reservations();
+        }
+
+        for (name, config) in secrets {
+            let secret = self.secrets.entry(name.clone()).or_insert_with(|| Secret {
+                name: name.clone(),
+                keys: HashMap::new(),
+                max_tunnels: 0,
+                sessions: HashMap::new(),
+                active_tunnels: vec![],
+                reservations: HashMap::new(),
+                usage: Arc::default(),
+                certificate_scopes: None,
+            });
+
+            secret.keys = config.keys.clone();
+            secret.certificate_scopes = config.certificate_scopes.clone();
+            secret.max_tunnels = config.max_tunnels;
+
+            let usage = secret.usage.clone();
+            let period_start = config.quota.period.start();
+            *usage.quota.write().unwrap() = config.quota.clone();
+
+            if usage.period_start.load(Ordering::Relaxed) != period_start {
+                self.load_usage(name, &usage, period_start);
+            }
+        }
+    }
+
+    /// Sets a user's counters to the traffic of the period starting at the
+    /// given time, so quotas are kept across restarts. The traffic already
+    /// in the storage is added once it is read, which happens after every
+    /// write queued so far, so nothing is counted twice.
+    fn load_usage(&self, user: &str, usage: &Arc<UserUsage>, period_start: u64) {
+        let mut pending = Traffic::default();
+
+        self.pending_traffic
+            .iter()
+            .filter(|((name, day), _)| name == user && *day >= period_start)
+            .for_each(|(_, traffic)| pending.add(traffic));
+
+        usage.period_start.store(period_start, Ordering::Relaxed);
+        usage
+            .connections
+            .store(pending.connections, Ordering::Relaxed);
+        usage
+            .bytes
+            .store(pending.bytes_in + pending.bytes_out, Ordering::Relaxed);
+
+        let user = user.to_string();
+        let usage = usage.clone();
+
+        self.storage.queue(move |storage| {
+            let days = match storage.traffic(Some(&user), period_start) {
+                Ok(days) => days,
+                Err(e) => {
+                    error!("failed to load traffic of user {user}: {e}");
+                    return;
+                }
+            };
+
+            // The period may have rolled over in the meantime.
+            if usage.period_start.load(Ordering::Relaxed) != period_start {
+                return;
+            }
+
+            for day in days {
+                usage
+                    .connections
+                    .fetch_add(day.traffic.connections, Ordering::Relaxed);
+                usage.bytes.fetch_add(
+                    day.traffic.bytes_in + day.traffic.bytes_out,
+                    Ordering::Relaxed,
+                );
+            }
+        });
+    }
+}
+
+impl UserUsage {
+    /// Why a new visitor should be refused, if any of the user's quotas is
+    /// exhausted.
+    pub fn check(&self) -> Option<&'static str> {
+        let quota = self.quota.read().unwrap();
+        self.roll_over(quota.period);
+
+        if quota
+            .max_connections
+            .is_some_and(|max| self.active_connections.load(Ordering::Relaxed) >= max)
+        {
+            Some("user connection limit reached")
+        } else if quota
+            .connections
+            .is_some_and(|max| self.connections.load(Ordering::Relaxed) >= max)
+        {
+            Some("connection quota exhausted")
+        } else if quota
+            .bytes
+            .is_some_and(|max| self.bytes.load(Ordering::Relaxed) >= max)
+        {
+            Some("bandwidth quota exhausted")
+        } else {
+            None
+        }
+    }
+
+    /// Resets the period's counters once the next period started.
+    pub fn roll_over(&self, period: QuotaPeriod) {
+        let start = period.start();
+
+        if self.period_start.load(Ordering::Relaxed) < start {
+            self.period_start.store(start, Ordering::Relaxed);
+            self.connections.store(0, Ordering::Relaxed);
+            self.bytes.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Secret {
+    /// Finds the unexpired key matching the given secret, along with its name.
+    pub fn find_key(&self, key: &str) -> Option<(&String, &Key)> {
+        self.keys
+            .iter()
+            .find(|(_, k)| !k.is_expired() && verify_key(key, &k.hash))
+    }
+
+    /// Stops every tunnel created by a session.
+    pub fn stop_session_tunnels(
+        &mut self,
+        session: &str,
+        listener_tx: &UnboundedSender<ListenerMessage>,
+    ) {
+        for tunnel in self.active_tunnels.iter().filter(|t| t.session == session) {
+            if let Err(e) = listener_tx.send(ListenerMessage::Stop { port: tunnel.port }) {
+                error!("failed to send stop message: {e}");
+            }
+        }
+
+        self.active_tunnels.retain(|t| t.session != session);
+    }
+
+    /// Stops every tunnel created by a session and gives up their reserved
+    /// ports, for sessions that removed their tunnels on purpose.
+    pub fn release_session_tunnels(
+        &mut self,
+        session: &str,
+        listener_tx: &UnboundedSender<ListenerMessage>,
+    ) {
+        for tunnel in self.active_tunnels.iter().filter(|t| t.session == session) {
+            self.reservations.remove(&tunnel.name);
+        }
+
+        self.stop_session_tunnels(session, listener_tx);
+    }
+
+    /// Number of tunnels held by the user: every reservation, online or
+    /// offline, plus the active tunnels that have none.
+    pub fn tunnel_count(&self) -> usize {
+        let unreserved_active = self
+            .active_tunnels
+            .iter()
+            .filter(|t| !self.reservations.contains_key(&t.name))
+            .count();
+
+        self.reservations.len() + unreserved_active
+    }
+
+    /// Stops every tunnel and worker of a session and marks it as closed.
+    pub fn close_session(&mut self, session: &str, listener_tx: &UnboundedSender<ListenerMessage>) {
+        self.stop_session_tunnels(session, listener_tx);
+
+        if let Some(session) = self.sessions.get_mut(session) {
+            session.closed = true;
+
+            for worker in session.workers.drain(..) {
+                if worker.close_tx.send(()).is_err() {
+                    error!("failed to send close message: {}", worker.client_addr);
+                }
+            }
+        }
+    }
+
+    /// Removes a session along with every tunnel and worker it created.
+    pub fn end_session(&mut self, session: &str, listener_tx: &UnboundedSender<ListenerMessage>) {
+        self.close_session(session, listener_tx);
+        self.sessions.remove(session);
+    }
+
+    /// Takes an idle worker from the session owning the tunnel on the given port.
+    pub fn take_worker(&mut self, port: u16) -> Option<Worker> {
+        let tunnel = self.active_tunnels.iter().find(|t| t.port == port)?;
+        let session = self.sessions.get_mut(&tunnel.session)?;
+
+        if session.workers.is_empty() {
+            return None;
+        }
+
+        let index = random::<usize>() % session.workers.len();
+        Some(session.workers.remove(index))
+    }
+
+    /// Ends every session whose lease ran out, returning their ids.
+    pub fn end_expired_sessions(
+        &mut self,
+        listener_tx: &UnboundedSender<ListenerMessage>,
+    ) -> Vec<String> {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.lease_expires <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for id in &expired {
+            self.end_session(id, listener_tx);
+        }
+
+        expired
+    }
+}
@@ -0,0 +1,182 @@
+//! Users, tunnel reservations and traffic accounting are kept behind the
+//! [`Storage`] trait. The configuration picks the backend: the TOML file
+//! itself by default, or a SQLite database.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+use log::error;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedSender},
+    oneshot,
+};
+
+use crate::{
+    auth::now,
+    config::{Configuration, Secret, StorageConfig},
+    state::Reservation,
+};
+
+use self::{sqlite::SqliteStorage, toml::TomlStorage};
+
+pub mod sqlite;
+pub mod toml;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// A tunnel's reservation along with its owner.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ReservationEntry {
+    pub user: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub reservation: Reservation,
+}
+
+/// Bytes and visitors relayed for a user.
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+pub struct Traffic {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Missing from files written before connections were counted.
+    #[serde(default)]
+    pub connections: u64,
+}
+
+impl Traffic {
+    pub fn add(&mut self, other: &Traffic) {
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.connections += other.connections;
+    }
+}
+
+/// A user's traffic during one day.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct DailyTraffic {
+    pub user: String,
+    /// Unix timestamp of the start of the day, in UTC.
+    pub day: u64,
+    #[serde(flatten)]
+    pub traffic: Traffic,
+}
+
+pub trait Storage: Send {
+    /// Every user with their keys and limits, keyed by name.
+    fn users(&self) -> Result<HashMap<String, Secret>>;
+
+    fn user(&self, name: &str) -> Result<Option<Secret>>;
+
+    /// Creates the user, or replaces it along with its keys.
+    fn save_user(&mut self, name: &str, secret: &Secret) -> Result<()>;
+
+    fn delete_user(&mut self, name: &str) -> Result<()>;
+
+    fn reservations(&self) -> Result<Vec<ReservationEntry>>;
+
+    /// Replaces every reservation.
+    fn save_reservations(&mut self, reservations: &[ReservationEntry]) -> Result<()>;
+
+    /// Adds to the user's traffic of the given day.
+    fn add_traffic(&mut self, user: &str, day: u64, traffic: &Traffic) -> Result<()>;
+
+    /// Traffic since the given day, of one user or all of them, oldest first.
+    fn traffic(&self, user: Option<&str>, since: u64) -> Result<Vec<DailyTraffic>>;
+}
+
+pub fn open_storage(cfg: &Configuration) -> Result<Box<dyn Storage>> {
+    match &cfg.storage {
+        StorageConfig::Toml => Ok(Box::new(TomlStorage::new(&cfg.path))),
+        StorageConfig::Sqlite { path } => Ok(Box::new(SqliteStorage::open(
+            &sqlite_path(&cfg.path, path),
+            cfg,
+        )?)),
+    }
+}
+
+/// Where the database is, relative to the configuration like the files kept
+/// next to it.
+pub fn sqlite_path(config_path: &Path, path: &Path) -> PathBuf {
+    match config_path.parent() {
+        Some(dir) => dir.join(path),
+        None => path.to_path_buf(),
+    }
+}
+
+type Job = Box<dyn FnOnce(&mut dyn Storage) + Send>;
+
+/// Runs the calls made to the storage one after another on a thread of its
+/// own, so the edge doesn't wait for file or database I/O while holding
+/// its state.
+#[derive(Clone)]
+pub struct StorageTask {
+    jobs: UnboundedSender<Job>,
+}
+
+impl StorageTask {
+    pub fn spawn(mut storage: Box<dyn Storage>) -> Self {
+        let (jobs, mut rx) = unbounded_channel::<Job>();
+
+        thread::spawn(move || {
+            while let Some(job) = rx.blocking_recv() {
+                job(storage.as_mut());
+            }
+        });
+
+        Self { jobs }
+    }
+
+    /// Queues a call after every one queued before, without waiting for it.
+    pub fn queue(&self, job: impl FnOnce(&mut dyn Storage) + Send + 'static) {
+        if self.jobs.send(Box::new(job)).is_err() {
+            error!("the storage task is gone");
+        }
+    }
+
+    /// Queues a call after every one queued before. The call is queued right
+    /// away, and the returned future resolves to its result.
+    pub fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut dyn Storage) -> T + Send + 'static,
+    ) -> impl Future<Output = Result<T>> {
+        let (tx, rx) = oneshot::channel();
+
+        self.queue(move |storage| {
+            let _ = tx.send(job(storage));
+        });
+
+        async move { rx.await.map_err(|_| anyhow!("the storage task is gone")) }
+    }
+}
+
+/// Start of the current day, in UTC.
+pub fn today() -> u64 {
+    now() / DAY * DAY
+}
+
+/// Start of the day `days` days ago.
+pub fn days_ago(days: u64) -> u64 {
+    today().saturating_sub(days * DAY)
+}
+
+/// Start of the current month, in UTC.
+pub fn month_start() -> u64 {
+    let days = now() / DAY;
+
+    // Day of the year counted from March 1st, so leap days come last
+    // (http://howardhinnant.github.io/date_algorithms.html#civil_from_days).
+    let day_of_era = (days + 719_468) % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * month + 2) / 5;
+
+    (days - day_of_month) * DAY
+}
@@ -0,0 +1,426 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    config::{Configuration, Key, Quota, Scope, Secret},
+    state::Reservation,
+};
+
+use super::{toml::TomlStorage, DailyTraffic, ReservationEntry, Storage, Traffic};
+
+/// Schema changes, applied in order. The database's `user_version` is the
+/// number of them already applied.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE users (
+        name TEXT PRIMARY KEY,
+        max_tunnels INTEGER NOT NULL
+    );
+
+    CREATE TABLE keys (
+        user TEXT NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        scopes TEXT NOT NULL,
+        expires_at INTEGER,
+        PRIMARY KEY (user, name)
+    );
+
+    CREATE TABLE reservations (
+        user TEXT NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        port INTEGER NOT NULL,
+        protocol TEXT NOT NULL,
+        mode TEXT NOT NULL,
+        PRIMARY KEY (user, name)
+    );
+
+    -- Kept when a user is deleted, as billing history.
+    CREATE TABLE traffic (
+        user TEXT NOT NULL,
+        day INTEGER NOT NULL,
+        bytes_in INTEGER NOT NULL,
+        bytes_out INTEGER NOT NULL,
+        PRIMARY KEY (user, day)
+    );
+",
+    "
+    ALTER TABLE users ADD COLUMN max_connections INTEGER;
+    ALTER TABLE users ADD COLUMN max_workers INTEGER;
+    ALTER TABLE users ADD COLUMN quota_bytes INTEGER;
+    ALTER TABLE users ADD COLUMN quota_connections INTEGER;
+    ALTER TABLE users ADD COLUMN quota_period TEXT NOT NULL DEFAULT 'Day';
+
+    ALTER TABLE traffic ADD COLUMN connections INTEGER NOT NULL DEFAULT 0;
+",
+    "
+    -- Comma-separated, or NULL if the user can't log in with a certificate.
+    ALTER TABLE users ADD COLUMN certificate_scopes TEXT;
+",
+];
+
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    /// Opens the database and brings its schema up to date. A new database
+    /// starts with everything the configuration file stored until then.
+    pub fn open(path: &Path, cfg: &Configuration) -> Result<Self> {
+        let conn = Connection::open(path)
+            .map_err(|e| anyhow!("unable to open database {}: {e}", path.display()))?;
+
+        // The CLI and a second edge being upgraded may use it concurrently.
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        let mut storage = Self { conn };
+
+        if storage.migrate()? {
+            storage.import(cfg)?;
+        }
+
+        Ok(storage)
+    }
+
+    /// Opens an existing database without creating, migrating or writing to
+    /// it. Returns `None` if its schema is older, which the edge upgrades
+    /// when it starts.
+    pub fn open_read_only(path: &Path) -> Result<Option<Self>> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| anyhow!("unable to open database {}: {e}", path.display()))?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        if version > MIGRATIONS.len() {
+            bail!("the database was created by a newer edge (schema version {version})");
+        }
+
+        Ok((version == MIGRATIONS.len()).then_some(Self { conn }))
+    }
+
+    /// Applies pending migrations, returning whether the database was new.
+    fn migrate(&mut self) -> Result<bool> {
+        let version: usize = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        if version > MIGRATIONS.len() {
+            bail!("the database was created by a newer edge (schema version {version})");
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = self.conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", index + 1)?;
+            tx.commit()?;
+
+            info!("migrated database to schema version {}", index + 1);
+        }
+
+        Ok(version == 0)
+    }
+
+    fn import(&mut self, cfg: &Configuration) -> Result<()> {
+        let previous = TomlStorage::new(&cfg.path);
+        let mut reservations = previous.reservations()?;
+        reservations.retain(|entry| cfg.secrets.contains_key(&entry.user));
+        let traffic = previous.traffic(None, 0)?;
+
+        if cfg.secrets.is_empty() && reservations.is_empty() && traffic.is_empty() {
+            return Ok(());
+        }
+
+        info!(
+            "importing {} user(s), {} reservation(s) and {} day(s) of traffic from {}...",
+            cfg.secrets.len(),
+            reservations.len(),
+            traffic.len(),
+            cfg.path.display()
+        );
+
+        for (name, secret) in &cfg.secrets {
+            self.save_user(name, secret)?;
+        }
+
+        self.save_reservations(&reservations)?;
+
+        for t in traffic {
+            self.add_traffic(&t.user, t.day, &t.traffic)?;
+        }
+
+        Ok(())
+    }
+
+    fn load_users(&self, name: Option<&str>) -> Result<HashMap<String, Secret>> {
+        let mut users = HashMap::new();
+
+        let mut statement = self.conn.prepare(
+            "SELECT name, max_tunnels, max_connections, max_workers, quota_bytes, quota_connections, quota_period, certificate_scopes
+             FROM users WHERE ?1 IS NULL OR name = ?1",
+        )?;
+        let rows = statement.query_map([name], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+                row.get::<_, String>(6)?,
+                row.get::<_, Option<String>>(7)?,
+            ))
+        })?;
+
+        for row in rows {
+            let (
+                name,
+                max_tunnels,
+                max_connections,
+                max_workers,
+                bytes,
+                connections,
+                period,
+                certificate_scopes,
+            ) = row?;
+
+            users.insert(
+                name,
+                Secret {
+                    max_tunnels,
+                    key: None,
+                    key_hash: None,
+                    keys: HashMap::new(),
+                    quota: Quota {
+                        max_connections,
+                        max_workers,
+                        bytes,
+                        connections,
+                        period: from_text(&period)?,
+                    },
+                    certificate_scopes: certificate_scopes
+                        .map(|scopes| from_scope_list(&scopes))
+                        .transpose()?,
+                },
+            );
+        }
+
+        let mut statement = self.conn.prepare(
+            "SELECT user, name, hash, scopes, expires_at FROM keys WHERE ?1 IS NULL OR user = ?1",
+        )?;
+        let rows = statement.query_map([name], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })?;
+
+        for row in rows {
+            let (user, name, hash, scopes, expires_at): (String, String, String, String, _) = row?;
+
+            let scopes = from_scope_list(&scopes)?;
+
+            if let Some(secret) = users.get_mut(&user) {
+                secret.keys.insert(name, Key::new(hash, scopes, expires_at));
+            }
+        }
+
+        Ok(users)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn users(&self) -> Result<HashMap<String, Secret>> {
+        self.load_users(None)
+    }
+
+    fn user(&self, name: &str) -> Result<Option<Secret>> {
+        Ok(self.load_users(Some(name))?.remove(name))
+    }
+
+    fn save_user(&mut self, name: &str, secret: &Secret) -> Result<()> {
+        let tx = self.conn.transaction()?;
+
+        let quota = &secret.quota;
+
+        tx.execute(
+            "INSERT INTO users (name, max_tunnels, max_connections, max_workers, quota_bytes, quota_connections, quota_period, certificate_scopes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (name) DO UPDATE SET
+                max_tunnels = excluded.max_tunnels,
+                max_connections = excluded.max_connections,
+                max_workers = excluded.max_workers,
+                quota_bytes = excluded.quota_bytes,
+                quota_connections = excluded.quota_connections,
+                quota_period = excluded.quota_period,
+                certificate_scopes = excluded.certificate_scopes",
+            params![
+                name,
+                secret.max_tunnels,
+                quota.max_connections,
+                quota.max_workers,
+                quota.bytes,
+                quota.connections,
+                to_text(&quota.period)?,
+                secret
+                    .certificate_scopes
+                    .as_deref()
+                    .map(to_scope_list)
+                    .transpose()?,
+            ],
+        )?;
+
+        tx.execute("DELETE FROM keys WHERE user = ?1", [name])?;
+
+        for (key_name, key) in &secret.keys {
+            let scopes = to_scope_list(&key.scopes)?;
+
+            tx.execute(
+                "INSERT INTO keys (user, name, hash, scopes, expires_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![name, key_name, key.hash, scopes, key.expires_at],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_user(&mut self, name: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM users WHERE name = ?1", [name])?;
+        Ok(())
+    }
+
+    fn reservations(&self) -> Result<Vec<ReservationEntry>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT user, name, port, protocol, mode FROM reservations")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+
+        let mut reservations = vec![];
+
+        for row in rows {
+            let (user, name, port, protocol, mode) = row?;
+
+            reservations.push(ReservationEntry {
+                user,
+                name,
+                reservation: Reservation {
+                    port,
+                    protocol: from_text(&protocol)?,
+                    mode: from_text(&mode)?,
+                },
+            });
+        }
+
+        Ok(reservations)
+    }
+
+    fn save_reservations(&mut self, reservations: &[ReservationEntry]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+
+        tx.execute("DELETE FROM reservations", [])?;
+
+        for entry in reservations {
+            tx.execute(
+                "INSERT INTO reservations (user, name, port, protocol, mode) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    entry.user,
+                    entry.name,
+                    entry.reservation.port,
+                    to_text(&entry.reservation.protocol)?,
+                    to_text(&entry.reservation.mode)?,
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn add_traffic(&mut self, user: &str, day: u64, traffic: &Traffic) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO traffic (user, day, bytes_in, bytes_out, connections) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (user, day) DO UPDATE SET
+                bytes_in = bytes_in + excluded.bytes_in,
+                bytes_out = bytes_out + excluded.bytes_out,
+                connections = connections + excluded.connections",
+            params![
+                user,
+                day,
+                traffic.bytes_in,
+                traffic.bytes_out,
+                traffic.connections
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn traffic(&self, user: Option<&str>, since: u64) -> Result<Vec<DailyTraffic>> {
+        let mut statement = self.conn.prepare(
+            "SELECT user, day, bytes_in, bytes_out, connections FROM traffic
+             WHERE day >= ?1 AND (?2 IS NULL OR user = ?2)
+             ORDER BY day, user",
+        )?;
+        let rows = statement.query_map(params![since, user], |row| {
+            Ok(DailyTraffic {
+                user: row.get(0)?,
+                day: row.get(1)?,
+                traffic: Traffic {
+                    bytes_in: row.get(2)?,
+                    bytes_out: row.get(3)?,
+                    connections: row.get(4)?,
+                },
+            })
+        })?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+/// Stores a unit enum variant by its name, e.g. `Tcp`.
+fn to_text<T: Serialize>(value: &T) -> Result<String> {
+    match serde_json::to_value(value)? {
+        Value::String(text) => Ok(text),
+        other => bail!("unexpected value {other}"),
+    }
+}
+
+fn from_text<T: DeserializeOwned>(text: &str) -> Result<T> {
+    serde_json::from_value(Value::String(text.to_string()))
+        .map_err(|e| anyhow!("invalid value {text:?} in database: {e}"))
+}
+
+fn to_scope_list(scopes: &[Scope]) -> Result<String> {
+    Ok(scopes
+        .iter()
+        .map(to_text)
+        .collect::<Result<Vec<_>>>()?
+        .join(","))
+}
+
+fn from_scope_list(scopes: &str) -> Result<Vec<Scope>> {
+    scopes
+        .split(',')
+        .filter(|scope| !scope.is_empty())
+        .map(from_text)
+        .collect()
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::config::{load_config, replace_file, with_suffix, write_config, Secret};
+
+use super::{days_ago, DailyTraffic, ReservationEntry, Storage, Traffic};
+
+/// Days of traffic kept in `config.toml.traffic.json`, which is rewritten on
+/// every flush. This covers monthly quotas and the default `usage` window,
+/// the SQLite backend keeps all of it.
+const TRAFFIC_RETENTION_DAYS: u64 = 90;
+
+/// Keeps users in the configuration file, which is read again for every
+/// operation so manual edits are picked up. Reservations go to a journal
+/// (`config.toml.tunnels.json`) and traffic to `config.toml.traffic.json`.
+pub struct TomlStorage {
+    path: PathBuf,
+}
+
+impl TomlStorage {
+    pub fn new(config_path: &Path) -> Self {
+        Self {
+            path: config_path.to_path_buf(),
+        }
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        with_suffix(&self.path, "tunnels.json")
+    }
+
+    fn traffic_path(&self) -> PathBuf {
+        with_suffix(&self.path, "traffic.json")
+    }
+}
+
+impl Storage for TomlStorage {
+    fn users(&self) -> Result<HashMap<String, Secret>> {
+        Ok(load_config(&self.path)?.secrets)
+    }
+
+    fn user(&self, name: &str) -> Result<Option<Secret>> {
+        Ok(self.users()?.remove(name))
+    }
+
+    fn save_user(&mut self, name: &str, secret: &Secret) -> Result<()> {
+        let mut cfg = load_config(&self.path)?;
+        cfg.secrets.insert(name.to_string(), secret.clone());
+        write_config(&cfg)
+    }
+
+    fn delete_user(&mut self, name: &str) -> Result<()> {
+        let mut cfg = load_config(&self.path)?;
+        cfg.secrets.remove(name);
+        write_config(&cfg)
+    }
+
+    fn reservations(&self) -> Result<Vec<ReservationEntry>> {
+        read_json(&self.journal_path())
+    }
+
+    fn save_reservations(&mut self, reservations: &[ReservationEntry]) -> Result<()> {
+        let mut reservations = reservations.to_vec();
+        reservations.sort_by(|a, b| (&a.user, &a.name).cmp(&(&b.user, &b.name)));
+
+        write_json(&self.journal_path(), &reservations)
+    }
+
+    fn add_traffic(&mut self, user: &str, day: u64, traffic: &Traffic) -> Result<()> {
+        let path = self.traffic_path();
+        let mut days: Vec<DailyTraffic> = read_json(&path)?;
+
+        let oldest = days_ago(TRAFFIC_RETENTION_DAYS);
+        days.retain(|t| t.day >= oldest);
+
+        match days.iter_mut().find(|t| t.user == user && t.day == day) {
+            Some(entry) => entry.traffic.add(traffic),
+            None => days.push(DailyTraffic {
+                user: user.to_string(),
+                day,
+                traffic: *traffic,
+            }),
+        }
+
+        write_json(&path, &days)
+    }
+
+    fn traffic(&self, user: Option<&str>, since: u64) -> Result<Vec<DailyTraffic>> {
+        let mut traffic: Vec<DailyTraffic> = read_json(&self.traffic_path())?;
+
+        traffic.retain(|t| t.day >= since && user.is_none_or(|user| t.user == user));
+        traffic.sort_by(|a, b| (a.day, &a.user).cmp(&(b.day, &b.user)));
+
+        Ok(traffic)
+    }
+}
+
+/// Reads a JSON list, which doesn't exist until something is first written.
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    let file = match fs::read_to_string(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(anyhow!("unable to read {}: {e}", path.display())),
+    };
+
+    serde_json::from_str(&file).map_err(|e| anyhow!("invalid file {}: {e}", path.display()))
+}
+
+/// Replaces the file atomically, like the configuration.
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    replace_file(path, &serde_json::to_vec_pretty(value)?)
+}
@@ -0,0 +1,117 @@
+use std::{any::Any, pin::Pin};
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::dev::Extensions;
+use anyhow::{anyhow, Result};
+use openssl::{
+    nid::Nid,
+    ssl::{Ssl, SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslRef, SslVerifyMode},
+    x509::{store::X509Lookup, verify::X509VerifyFlags, X509Name, X509VerifyResult},
+};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+use crate::config::{CertificateIdentity, TlsConfig};
+
+/// Names a verified client certificate was issued to, kept with the
+/// connection it was presented on. They are lowercased like user names.
+#[derive(Clone)]
+pub struct ClientCertificate(pub Vec<String>);
+
+/// Sets up TLS with the edge's certificate. With a client CA, clients are
+/// asked for a certificate, but those without one can still use a key.
+pub fn acceptor(cfg: &TlsConfig) -> Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+
+    builder
+        .set_certificate_chain_file(&cfg.cert)
+        .map_err(|e| anyhow!("unable to load certificate {}: {e}", cfg.cert.display()))?;
+    builder
+        .set_private_key_file(&cfg.key, SslFiletype::PEM)
+        .map_err(|e| anyhow!("unable to load private key {}: {e}", cfg.key.display()))?;
+    builder.check_private_key().map_err(|e| {
+        anyhow!(
+            "private key {} does not match certificate {}: {e}",
+            cfg.key.display(),
+            cfg.cert.display()
+        )
+    })?;
+
+    if let Some(client_ca) = &cfg.client_ca {
+        builder
+            .set_ca_file(client_ca)
+            .map_err(|e| anyhow!("unable to load client CA {}: {e}", client_ca.display()))?;
+        builder.set_client_ca_list(X509Name::load_client_ca_file(client_ca)?);
+        builder.set_verify(SslVerifyMode::PEER);
+
+        if let Some(crl) = &cfg.crl {
+            let store = builder.cert_store_mut();
+            store
+                .add_lookup(X509Lookup::file())?
+                .load_crl_file(crl, SslFiletype::PEM)
+                .map_err(|e| anyhow!("unable to load CRL {}: {e}", crl.display()))?;
+            store.set_flags(X509VerifyFlags::CRL_CHECK)?;
+        }
+    }
+
+    Ok(builder)
+}
+
+/// The client certificate presented on the connection, if there is one and
+/// it was issued by the client CA.
+pub fn client_certificate(
+    ssl: &SslRef,
+    identity: CertificateIdentity,
+) -> Option<ClientCertificate> {
+    if ssl.verify_result() != X509VerifyResult::OK {
+        return None;
+    }
+
+    let cert = ssl.peer_certificate()?;
+
+    let names = match identity {
+        CertificateIdentity::CommonName => cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .filter_map(|entry| entry.data().to_string().ok())
+            .map(|name| name.to_lowercase())
+            .collect(),
+        CertificateIdentity::SubjectAltName => cert
+            .subject_alt_names()
+            .into_iter()
+            .flatten()
+            .filter_map(|name| {
+                name.dnsname()
+                    .or_else(|| name.email())
+                    .or_else(|| name.uri())
+                    .map(str::to_lowercase)
+            })
+            .collect(),
+    };
+
+    Some(ClientCertificate(names))
+}
+
+/// Keeps the client certificate of an API connection with the connection,
+/// where the requests made on it find it.
+pub fn keep_client_certificate(
+    conn: &dyn Any,
+    extensions: &mut Extensions,
+    identity: CertificateIdentity,
+) {
+    let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+
+    if let Some(certificate) = client_certificate(stream.ssl(), identity) {
+        extensions.insert(certificate);
+    }
+}
+
+/// Completes the TLS handshake of a worker connection.
+pub async fn accept(acceptor: &SslAcceptor, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+    let mut stream = SslStream::new(Ssl::new(acceptor.context())?, stream)?;
+    Pin::new(&mut stream).accept().await?;
+
+    Ok(stream)
+}
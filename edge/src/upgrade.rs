@@ -0,0 +1,472 @@
+//! Zero-downtime upgrades. On SIGUSR2 the running edge starts its binary
+//! again and passes it every listening socket over a Unix socket
+//! (SCM_RIGHTS), along with its sessions and tunnels. Once the new process
+//! accepts on them, the old one drains and exits.
+
+use std::{
+    env, fs,
+    io::{IoSlice, IoSliceMut, Read, Write},
+    iter::zip,
+    net::TcpListener,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{fs::PermissionsExt, net::UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use log::{error, info, warn};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::UnixListener,
+    process::Command,
+    select,
+    signal::unix::{signal, SignalKind},
+    sync::{oneshot, Mutex},
+    task::spawn_blocking,
+    time::timeout,
+};
+
+use crate::{
+    cli::serve::Listeners,
+    config::with_suffix,
+    listener::{
+        proxy::{TunnelBind, TunnelSpec},
+        ListenerMessage,
+    },
+    state::{Session, State, Tunnel, TunnelStats},
+};
+
+/// Set on the new process, pointing at the socket the old one hands over on.
+const UPGRADE_ENV: &str = "EDGE_UPGRADE_FROM";
+
+/// How long either side waits for the other during a handoff.
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The kernel refuses more descriptors in a single message (SCM_MAX_FD).
+const MAX_FDS_PER_MESSAGE: usize = 250;
+
+const READY: &[u8] = b"ready\n";
+
+/// Sessions and tunnels passed on to the new process. Tunnels are in the
+/// same order as their sockets.
+#[derive(Serialize, Deserialize)]
+struct Handoff {
+    sessions: Vec<SessionHandoff>,
+    tunnels: Vec<TunnelHandoff>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionHandoff {
+    user: String,
+    id: String,
+    client_addr: String,
+    closed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TunnelHandoff {
+    user: String,
+    name: String,
+    spec: TunnelSpec,
+    session: String,
+    created_at: u64,
+    total_connections: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// What the new process receives from the edge it replaces.
+pub struct Takeover {
+    handoff: Handoff,
+    tunnels: Vec<TcpListener>,
+    parent: UnixStream,
+}
+
+/// Hands the edge over to a new process every time SIGUSR2 is received,
+/// and returns once one took over. The edge keeps serving if an upgrade fails.
+pub async fn wait_for_upgrade(state: Arc<Mutex<State>>, sockets: Listeners, config_path: PathBuf) {
+    // Resolved before the binary is replaced on disk.
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            error!("unable to find the edge binary, upgrades are disabled: {e}");
+            return std::future::pending().await;
+        }
+    };
+
+    let mut upgrade = match signal(SignalKind::user_defined2()) {
+        Ok(upgrade) => upgrade,
+        Err(e) => {
+            error!("failed to listen for SIGUSR2, upgrades are disabled: {e}");
+            return std::future::pending().await;
+        }
+    };
+
+    loop {
+        upgrade.recv().await;
+
+        info!("upgrading, starting {}...", exe.display());
+
+        match hand_over(&state, &sockets, &exe, &config_path).await {
+            Ok(pid) => {
+                info!("edge {pid} took over");
+                return;
+            }
+            Err(e) => error!("upgrade failed, still serving: {e}"),
+        }
+    }
+}
+
+/// Starts the new edge and passes it the sockets and state. Returns its pid.
+async fn hand_over(
+    state: &Arc<Mutex<State>>,
+    sockets: &Listeners,
+    exe: &Path,
+    config_path: &Path,
+) -> Result<u32> {
+    let path = with_suffix(config_path, "upgrade.sock");
+
+    // Left behind by an upgrade that did not finish.
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+
+    let mut child = Command::new(exe)
+        .args(env::args_os().skip(1))
+        .env(UPGRADE_ENV, &path)
+        .spawn()
+        .map_err(|e| anyhow!("unable to start {}: {e}", exe.display()))?;
+    let pid = child
+        .id()
+        .ok_or_else(|| anyhow!("the new edge exited right away"))?;
+
+    let result = select! {
+        result = send_handoff(state, sockets, &listener, pid) => result,
+        status = child.wait() => match status {
+            Ok(status) => Err(anyhow!("the new edge exited ({status})")),
+            Err(e) => Err(e.into()),
+        },
+    };
+
+    let _ = fs::remove_file(&path);
+
+    match result {
+        Ok(()) => Ok(pid),
+        Err(e) => {
+            let _ = child.kill().await;
+            Err(e)
+        }
+    }
+}
+
+async fn send_handoff(
+    state: &Arc<Mutex<State>>,
+    sockets: &Listeners,
+    listener: &UnixListener,
+    pid: u32,
+) -> Result<()> {
+    let stream = timeout(HANDOFF_TIMEOUT, accept_child(listener, pid))
+        .await
+        .map_err(|_| anyhow!("the new edge did not connect in time"))??;
+
+    // The new edge loads its users' quota usage once it has the handoff.
+    State::flush_traffic(state).await;
+
+    let (handoff, fds) = {
+        let state = state.lock().await;
+
+        let (tx, rx) = oneshot::channel();
+        state
+            .listener_tx
+            .send(ListenerMessage::Export { reply: tx })
+            .map_err(|_| anyhow!("failed to request tunnel sockets"))?;
+        let mut tunnel_sockets = rx.await?;
+
+        let mut fds = vec![
+            OwnedFd::from(sockets.api.try_clone()?),
+            OwnedFd::from(sockets.worker.try_clone()?),
+        ];
+        let mut handoff = Handoff {
+            sessions: vec![],
+            tunnels: vec![],
+        };
+
+        for secret in state.secrets.values() {
+            for (id, session) in &secret.sessions {
+                handoff.sessions.push(SessionHandoff {
+                    user: secret.name.clone(),
+                    id: id.clone(),
+                    client_addr: session.client_addr.clone(),
+                    closed: session.closed,
+                });
+            }
+
+            for tunnel in &secret.active_tunnels {
+                let Some(socket) = tunnel_sockets.remove(&tunnel.port) else {
+                    warn!(
+                        "no socket for tunnel {} on port {}",
+                        tunnel.name, tunnel.port
+                    );
+                    continue;
+                };
+
+                fds.push(socket);
+                handoff.tunnels.push(TunnelHandoff {
+                    user: secret.name.clone(),
+                    name: tunnel.name.clone(),
+                    spec: tunnel.spec.clone(),
+                    session: tunnel.session.clone(),
+                    created_at: tunnel.created_at,
+                    total_connections: tunnel.stats.total_connections.load(Ordering::Relaxed),
+                    bytes_in: tunnel.stats.bytes_in.load(Ordering::Relaxed),
+                    bytes_out: tunnel.stats.bytes_out.load(Ordering::Relaxed),
+                });
+            }
+        }
+
+        (handoff, fds)
+    };
+
+    info!(
+        "handing over {} session(s) and {} tunnel(s)...",
+        handoff.sessions.len(),
+        handoff.tunnels.len()
+    );
+
+    let mut stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+
+    spawn_blocking(move || {
+        let payload = serde_json::to_vec(&handoff)?;
+        stream.write_all(&(payload.len() as u64).to_be_bytes())?;
+        stream.write_all(&payload)?;
+
+        send_fds(&stream, &fds)?;
+
+        let mut ready = [0; READY.len()];
+        stream
+            .read_exact(&mut ready)
+            .map_err(|e| anyhow!("the new edge did not take over: {e}"))?;
+
+        if ready != READY {
+            bail!("unexpected answer from the new edge");
+        }
+
+        Ok(())
+    })
+    .await?
+}
+
+/// Accepts the connection of the process started for the upgrade. Anything
+/// else connecting to the socket is turned away.
+async fn accept_child(listener: &UnixListener, pid: u32) -> Result<tokio::net::UnixStream> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        match stream.peer_cred()?.pid() {
+            Some(peer) if peer as u32 == pid => return Ok(stream),
+            peer => warn!("refused handoff to process {peer:?}, expected {pid}"),
+        }
+    }
+}
+
+/// Connects to the edge being upgraded when this process was started by
+/// one, and receives its sockets and state.
+pub fn take_over() -> Result<Option<(Listeners, Takeover)>> {
+    let Some(path) = env::var_os(UPGRADE_ENV) else {
+        return Ok(None);
+    };
+
+    info!("taking over from the edge on {}...", path.to_string_lossy());
+
+    let mut parent = UnixStream::connect(&path)
+        .map_err(|e| anyhow!("unable to connect to the edge being upgraded: {e}"))?;
+    parent.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+
+    let mut len = [0; 8];
+    parent.read_exact(&mut len)?;
+
+    let mut payload = vec![0; u64::from_be_bytes(len) as usize];
+    parent.read_exact(&mut payload)?;
+
+    let handoff: Handoff = serde_json::from_slice(&payload)?;
+
+    let mut sockets = recv_fds(&parent, 2 + handoff.tunnels.len())?
+        .into_iter()
+        .map(TcpListener::from);
+
+    let (Some(api), Some(worker)) = (sockets.next(), sockets.next()) else {
+        bail!("the edge being upgraded sent no API or worker socket");
+    };
+
+    Ok(Some((
+        Listeners { api, worker },
+        Takeover {
+            handoff,
+            tunnels: sockets.collect(),
+            parent,
+        },
+    )))
+}
+
+/// Restores the sessions and tunnels of the edge being replaced, on the
+/// sockets it passed on. Returns the connection to confirm the takeover on.
+pub async fn adopt(state: &Arc<Mutex<State>>, takeover: Takeover) -> UnixStream {
+    let Takeover {
+        handoff,
+        tunnels,
+        parent,
+    } = takeover;
+
+    let mut state = state.lock().await;
+    let listener_tx = state.listener_tx.clone();
+    let lease = Duration::from_secs(state.cfg.session_lease);
+
+    for session in handoff.sessions {
+        let Some(secret) = state.secrets.get_mut(&session.user) else {
+            warn!(
+                "dropping session {} of removed user {}",
+                session.id, session.user
+            );
+            continue;
+        };
+
+        secret.sessions.insert(
+            session.id,
+            Session {
+                client_addr: session.client_addr,
+                lease_expires: Instant::now() + lease,
+                workers: vec![],
+                closed: session.closed,
+            },
+        );
+    }
+
+    let mut restored = 0;
+
+    for (tunnel, socket) in zip(handoff.tunnels, tunnels) {
+        let Some(secret) = state
+            .secrets
+            .get_mut(&tunnel.user)
+            .filter(|secret| secret.sessions.contains_key(&tunnel.session))
+        else {
+            warn!("dropping tunnel {} of user {}", tunnel.name, tunnel.user);
+            continue;
+        };
+
+        let stats = Arc::new(TunnelStats {
+            total_connections: tunnel.total_connections.into(),
+            bytes_in: tunnel.bytes_in.into(),
+            bytes_out: tunnel.bytes_out.into(),
+            ..TunnelStats::new(secret.usage.clone())
+        });
+
+        let (tx, rx) = oneshot::channel();
+
+        if listener_tx
+            .send(ListenerMessage::Listen {
+                reply: tx,
+                spec: tunnel.spec.clone(),
+                name: tunnel.name.clone(),
+                user: tunnel.user.clone(),
+                bind: TunnelBind::Inherited(socket),
+                stats: stats.clone(),
+            })
+            .is_err()
+        {
+            error!("failed to request listener for tunnel {}", tunnel.name);
+            continue;
+        }
+
+        match rx.await {
+            Ok(Some(port)) => {
+                secret.active_tunnels.push(Tunnel {
+                    name: tunnel.name,
+                    spec: tunnel.spec,
+                    port,
+                    session: tunnel.session,
+                    created_at: tunnel.created_at,
+                    stats,
+                });
+
+                restored += 1;
+            }
+            _ => error!("failed to restore tunnel {}", tunnel.name),
+        }
+    }
+
+    info!("took over {restored} tunnel(s)");
+
+    parent
+}
+
+/// Tells the edge being replaced that this one is accepting, so it can drain.
+pub fn confirm(mut parent: UnixStream) -> Result<()> {
+    parent.write_all(READY)?;
+    Ok(())
+}
+
+fn send_fds(stream: &UnixStream, fds: &[OwnedFd]) -> Result<()> {
+    let fds = fds.iter().map(|fd| fd.as_raw_fd()).collect::<Vec<_>>();
+
+    // Every chunk travels with a single byte, so the receiver reads them
+    // one message at a time.
+    for chunk in fds.chunks(MAX_FDS_PER_MESSAGE) {
+        sendmsg::<()>(
+            stream.as_raw_fd(),
+            &[IoSlice::new(&[0])],
+            &[ControlMessage::ScmRights(chunk)],
+            MsgFlags::empty(),
+            None,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn recv_fds(stream: &UnixStream, count: usize) -> Result<Vec<OwnedFd>> {
+    let mut fds = Vec::with_capacity(count);
+
+    while fds.len() < count {
+        let mut byte = [0];
+        let mut iov = [IoSliceMut::new(&mut byte)];
+        let mut cmsg = nix::cmsg_space!([RawFd; MAX_FDS_PER_MESSAGE]);
+
+        let message = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+
+        if message.bytes == 0 {
+            bail!("the edge being upgraded closed the connection");
+        }
+
+        for cmsg in message.cmsgs() {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                // SAFETY: the kernel just installed these descriptors for
+                // this process, nothing else owns them.
+                fds.extend(
+                    received
+                        .into_iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                );
+            }
+        }
+
+        // Descriptors beyond the control buffer were dropped by the kernel,
+        // so the tunnels would no longer line up with their sockets.
+        if message.flags.contains(MsgFlags::MSG_CTRUNC) {
+            bail!("descriptors from the edge being upgraded were truncated");
+        }
+    }
+
+    Ok(fds)
+}
@@ -0,0 +1,40 @@
+//! Log setup shared by the client and the edge.
+
+use std::io::Write;
+
+use clap::ValueEnum;
+use serde_json::json;
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log pipelines
+    Json,
+}
+
+pub fn init_logging(format: LogFormat) {
+    if cfg!(debug_assertions) {
+        std::env::set_var("RUST_LOG", "debug");
+    } else if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+
+    let mut builder = env_logger::Builder::from_default_env();
+
+    if let LogFormat::Json = format {
+        builder.format(|buf, record| {
+            let line = json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+
+            writeln!(buf, "{line}")
+        });
+    }
+
+    builder.init();
+}